@@ -0,0 +1,180 @@
+/*
+ * a blue noise mask for the blue noise sampler in compute.wgsl
+ * every value from 0 to 1 shows up once and similar values are never close together,
+ * so offsetting the samples of every pixel by it spreads the error out evenly over the image
+ * made with void and cluster, https://cv.ulichney.com/papers/1993-void-cluster.pdf
+ */
+pub(crate) const SIZE: usize = 64;
+
+// width of the gaussian that decides how clustered a point is, 1.5 like in the paper
+const SIGMA: f32 = 1.5;
+
+/*
+ * the rank of every pixel over SIZE * SIZE, row by row
+ * always the same mask, the initial pattern comes from a fixed seed
+ */
+pub(crate) fn generate() -> Vec<f32> {
+    let n = SIZE * SIZE;
+    // gaussian of the distance on a torus so the mask tiles
+    let kernel = (0..n)
+        .map(|i| {
+            let wrap = |d: usize| d.min(SIZE - d) as f32;
+            let (dx, dy) = (wrap(i % SIZE), wrap(i / SIZE));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect::<Vec<f32>>();
+    let mut pattern = Pattern {
+        ones: vec![false; n],
+        energy: vec![0.0; n],
+        kernel,
+    };
+
+    // a tenth of the pixels at random, then moved apart until it is as even as it gets
+    let mut state = 0x9e3779b9u32;
+    let mut count = 0;
+    while count < n / 10 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let i = state as usize % n;
+        if !pattern.ones[i] {
+            pattern.toggle(i);
+            count += 1;
+        }
+    }
+    loop {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+    let prototype = pattern.ones.clone();
+
+    let mut rank = vec![0; n];
+    // take the initial points away from the tightest clusters, they get the ranks below count
+    for r in (0..count).rev() {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        rank[cluster] = r;
+    }
+    // then fill up the largest voids from the prototype until half is set
+    pattern.reset(&prototype);
+    for r in count..n / 2 {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        rank[void] = r;
+    }
+    // past half the zeros are the minority, so the tightest cluster of zeros is the next to set
+    pattern.reset(&pattern.ones.iter().map(|&one| !one).collect::<Vec<bool>>());
+    for r in n / 2..n {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        rank[cluster] = r;
+    }
+
+    rank.into_iter().map(|r| r as f32 / n as f32).collect()
+}
+
+struct Pattern {
+    ones: Vec<bool>,
+    // the kernel summed over all ones, high where they cluster
+    energy: Vec<f32>,
+    kernel: Vec<f32>,
+}
+
+impl Pattern {
+    fn toggle(&mut self, i: usize) {
+        self.ones[i] = !self.ones[i];
+        let sign = if self.ones[i] { 1.0 } else { -1.0 };
+        let (x, y) = (i % SIZE, i / SIZE);
+        for (j, e) in self.energy.iter_mut().enumerate() {
+            let dx = (j % SIZE + SIZE - x) % SIZE;
+            let dy = (j / SIZE + SIZE - y) % SIZE;
+            *e += sign * self.kernel[dy * SIZE + dx];
+        }
+    }
+
+    fn reset(&mut self, ones: &[bool]) {
+        self.ones.fill(false);
+        self.energy.fill(0.0);
+        for (i, &one) in ones.iter().enumerate() {
+            if one {
+                self.toggle(i);
+            }
+        }
+    }
+
+    // the set pixel with the most energy
+    fn tightest_cluster(&self) -> usize {
+        (0..self.ones.len())
+            .filter(|&i| self.ones[i])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    // the empty pixel with the least energy
+    fn largest_void(&self) -> usize {
+        (0..self.ones.len())
+            .filter(|&i| !self.ones[i])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+// single channel float texture of a square mask, the shader reads it with textureLoad
+pub(crate) fn create_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mask: &[f32],
+) -> wgpu::TextureView {
+    let side = mask.len().isqrt() as u32;
+    let size = wgpu::Extent3d {
+        width: side,
+        height: side,
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Blue Noise Texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::R32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    queue.write_texture(
+        wgpu::TexelCopyTextureInfo {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        bytemuck::cast_slice(mask),
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * side),
+            rows_per_image: Some(side),
+        },
+        size,
+    );
+    texture.create_view(&Default::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_rank_once() {
+        let mut ranks = generate()
+            .iter()
+            .map(|&v| (v * (SIZE * SIZE) as f32) as usize)
+            .collect::<Vec<usize>>();
+        ranks.sort();
+        assert!(ranks.into_iter().eq(0..SIZE * SIZE));
+    }
+}
@@ -0,0 +1,379 @@
+use glam::{Affine3A, Vec3};
+
+/*
+ * bounding volume hierarchy over a list of boxes
+ * used both for the triangles of a mesh and for the instances of a scene
+ * built once on the cpu and then only read by the compute shader
+ * the tree is stored flat, the two children of a node are always next to each other
+ * so an interior node only needs to know where its left child is
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BvhNode {
+    pub aabb_min: [f32; 3],
+    // index of the left child for interior nodes, first primitive for leaves
+    pub left_or_first: u32,
+    pub aabb_max: [f32; 3],
+    // 0 for interior nodes
+    pub count: u32,
+}
+
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    // primitives in the order the leaves refer to them
+    pub order: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn bounds(&self) -> Aabb {
+        Aabb {
+            min: Vec3::from(self.nodes[0].aabb_min),
+            max: Vec3::from(self.nodes[0].aabb_max),
+        }
+    }
+
+    /*
+     * move the nodes to the end of a buffer shared by several trees
+     * and point the leaves at primitives starting at prim_offset
+     * returns the index of the root
+     */
+    pub fn append_to(self, nodes: &mut Vec<BvhNode>, prim_offset: u32) -> u32 {
+        let node_offset = nodes.len() as u32;
+        nodes.extend(self.nodes.into_iter().map(|mut n| {
+            if n.count == 0 {
+                n.left_or_first += node_offset;
+            } else {
+                n.left_or_first += prim_offset;
+            }
+            n
+        }));
+        node_offset
+    }
+}
+
+// leaves with this many primitives or less are never split
+const MAX_LEAF_SIZE: usize = 2;
+// number of buckets the centroids are sorted into when looking for the best split
+const BINS: usize = 12;
+// cost of traversing a node relative to intersecting a primitive
+const TRAVERSAL_COST: f32 = 1.0;
+/*
+ * nodes this deep are always leaves, the shader keeps one stack entry for every interior node
+ * above the one it is at, so this has to be BVH_STACK_SIZE in compute.wgsl
+ */
+pub(crate) const MAX_DEPTH: usize = 64;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Aabb {
+        let mut aabb = Aabb::EMPTY;
+        for p in points {
+            aabb.grow(p);
+        }
+        aabb
+    }
+
+    pub fn grow(&mut self, p: Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    pub fn union(&mut self, other: &Aabb) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    // the box around this box after it has been moved by transform
+    pub fn transform(&self, transform: &Affine3A) -> Aabb {
+        Aabb::from_points((0..8).map(|i| {
+            transform.transform_point3(Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            ))
+        }))
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    fn area(&self) -> f32 {
+        if self.min.x > self.max.x {
+            return 0.0;
+        }
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+}
+
+struct BuildPrim {
+    index: u32,
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+/*
+ * build the tree with the surface area heuristic
+ * at every node the centroids are binned along each axis
+ * and we split where the estimated cost of tracing through the children is lowest
+ * if no split is cheaper than intersecting every primitive the node becomes a leaf
+ * and so does every node at MAX_DEPTH, however many primitives are left
+ */
+pub fn build(bounds: &[Aabb]) -> Bvh {
+    build_to_depth(bounds, MAX_DEPTH)
+}
+
+fn build_to_depth(bounds: &[Aabb], max_depth: usize) -> Bvh {
+    let mut prims = bounds
+        .iter()
+        .enumerate()
+        .map(|(index, bounds)| BuildPrim {
+            index: index as u32,
+            bounds: *bounds,
+            centroid: bounds.centroid(),
+        })
+        .collect::<Vec<BuildPrim>>();
+
+    let mut nodes = Vec::with_capacity(prims.len() * 2);
+    nodes.push(BvhNode {
+        aabb_min: [0.0; 3],
+        left_or_first: 0,
+        aabb_max: [0.0; 3],
+        count: prims.len() as u32,
+    });
+    subdivide(&mut nodes, &mut prims, 0, max_depth);
+
+    Bvh {
+        nodes,
+        order: prims.into_iter().map(|p| p.index).collect(),
+    }
+}
+
+// max_depth counts down, the node becomes a leaf at 0
+fn subdivide(nodes: &mut Vec<BvhNode>, prims: &mut [BuildPrim], node_idx: usize, max_depth: usize) {
+    let first = nodes[node_idx].left_or_first as usize;
+    let count = nodes[node_idx].count as usize;
+    let node_prims = &mut prims[first..first + count];
+
+    let mut bounds = Aabb::EMPTY;
+    let mut centroid_bounds = Aabb::EMPTY;
+    for p in node_prims.iter() {
+        bounds.union(&p.bounds);
+        centroid_bounds.grow(p.centroid);
+    }
+    nodes[node_idx].aabb_min = bounds.min.to_array();
+    nodes[node_idx].aabb_max = bounds.max.to_array();
+
+    if count <= MAX_LEAF_SIZE || max_depth == 0 {
+        return;
+    }
+
+    let Some((axis, split_pos, split_cost)) = find_split(node_prims, &centroid_bounds) else {
+        return;
+    };
+    let leaf_cost = count as f32 * bounds.area();
+    if split_cost + TRAVERSAL_COST * bounds.area() >= leaf_cost {
+        return;
+    }
+
+    // partition in place so each child owns a contiguous range of primitives
+    let mut i = 0;
+    let mut j = count;
+    while i < j {
+        if node_prims[i].centroid[axis] < split_pos {
+            i += 1;
+        } else {
+            j -= 1;
+            node_prims.swap(i, j);
+        }
+    }
+    let left_count = i;
+    if left_count == 0 || left_count == count {
+        return;
+    }
+
+    let left_idx = nodes.len();
+    nodes.push(BvhNode {
+        aabb_min: [0.0; 3],
+        left_or_first: first as u32,
+        aabb_max: [0.0; 3],
+        count: left_count as u32,
+    });
+    nodes.push(BvhNode {
+        aabb_min: [0.0; 3],
+        left_or_first: (first + left_count) as u32,
+        aabb_max: [0.0; 3],
+        count: (count - left_count) as u32,
+    });
+    nodes[node_idx].left_or_first = left_idx as u32;
+    nodes[node_idx].count = 0;
+
+    subdivide(nodes, prims, left_idx, max_depth - 1);
+    subdivide(nodes, prims, left_idx + 1, max_depth - 1);
+}
+
+// returns the axis, the position along it and the cost of the cheapest split
+fn find_split(prims: &[BuildPrim], centroid_bounds: &Aabb) -> Option<(usize, f32, f32)> {
+    let mut best: Option<(usize, f32, f32)> = None;
+    for axis in 0..3 {
+        let lo = centroid_bounds.min[axis];
+        let hi = centroid_bounds.max[axis];
+        if hi <= lo {
+            continue;
+        }
+        let scale = BINS as f32 / (hi - lo);
+
+        let mut bin_bounds = [Aabb::EMPTY; BINS];
+        let mut bin_counts = [0usize; BINS];
+        for p in prims {
+            let b = (((p.centroid[axis] - lo) * scale) as usize).min(BINS - 1);
+            bin_counts[b] += 1;
+            bin_bounds[b].union(&p.bounds);
+        }
+
+        // sweep from both sides to get the area and count left and right of every plane
+        let mut left_area = [0.0; BINS - 1];
+        let mut left_count = [0; BINS - 1];
+        let mut right_area = [0.0; BINS - 1];
+        let mut right_count = [0; BINS - 1];
+        let mut left_box = Aabb::EMPTY;
+        let mut right_box = Aabb::EMPTY;
+        let mut left_sum = 0;
+        let mut right_sum = 0;
+        for i in 0..BINS - 1 {
+            left_sum += bin_counts[i];
+            left_count[i] = left_sum;
+            left_box.union(&bin_bounds[i]);
+            left_area[i] = left_box.area();
+
+            right_sum += bin_counts[BINS - 1 - i];
+            right_count[BINS - 2 - i] = right_sum;
+            right_box.union(&bin_bounds[BINS - 1 - i]);
+            right_area[BINS - 2 - i] = right_box.area();
+        }
+
+        for i in 0..BINS - 1 {
+            let cost = left_count[i] as f32 * left_area[i] + right_count[i] as f32 * right_area[i];
+            if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, lo + (i + 1) as f32 / scale, cost));
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(p: Vec3) -> Aabb {
+        Aabb {
+            min: p,
+            max: p + Vec3::ONE,
+        }
+    }
+
+    fn contains(outer: &BvhNode, inner: &BvhNode) -> bool {
+        (0..3).all(|a| {
+            outer.aabb_min[a] <= inner.aabb_min[a] && inner.aabb_max[a] <= outer.aabb_max[a]
+        })
+    }
+
+    /*
+     * walks the tree checking that every child lies in its parent,
+     * counts how often each primitive is reached and returns the depth of the deepest leaf
+     */
+    fn walk(bvh: &Bvh, node: usize, bounds: &[Aabb], seen: &mut [u32]) -> usize {
+        let n = &bvh.nodes[node];
+        if n.count > 0 {
+            let first = n.left_or_first as usize;
+            for &prim in &bvh.order[first..first + n.count as usize] {
+                seen[prim as usize] += 1;
+                let b = &bounds[prim as usize];
+                assert!(b.min.cmpge(Vec3::from(n.aabb_min)).all());
+                assert!(b.max.cmple(Vec3::from(n.aabb_max)).all());
+            }
+            return 0;
+        }
+        let left = n.left_or_first as usize;
+        let mut depth = 0;
+        for child in [left, left + 1] {
+            assert!(
+                contains(n, &bvh.nodes[child]),
+                "node {} is not in its parent",
+                child
+            );
+            depth = depth.max(1 + walk(bvh, child, bounds, seen));
+        }
+        depth
+    }
+
+    fn check(bounds: &[Aabb], max_depth: usize) -> usize {
+        let bvh = build_to_depth(bounds, max_depth);
+        let mut seen = vec![0; bounds.len()];
+        let depth = walk(&bvh, 0, bounds, &mut seen);
+        assert!(
+            seen.iter().all(|&s| s == 1),
+            "primitives reached {:?} times",
+            seen
+        );
+        depth
+    }
+
+    #[test]
+    fn every_primitive_once_and_children_in_parents() {
+        // a grid with some random jitter so the splits are not all even
+        let mut state = 1u32;
+        let mut random = || {
+            state = state.wrapping_mul(747796405).wrapping_add(2891336453);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        let bounds = (0..1000)
+            .map(|i| {
+                let p = Vec3::new((i % 10) as f32, (i / 10 % 10) as f32, (i / 100) as f32);
+                Aabb {
+                    min: p * 3.0,
+                    max: p * 3.0 + Vec3::new(random(), random(), random()) * 5.0,
+                }
+            })
+            .collect::<Vec<Aabb>>();
+        assert!(check(&bounds, MAX_DEPTH) <= MAX_DEPTH);
+    }
+
+    #[test]
+    fn a_single_primitive() {
+        let bvh = build(&[unit_box(Vec3::ZERO)]);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 1);
+        assert_eq!(bvh.order, [0]);
+    }
+
+    #[test]
+    fn stops_at_the_depth_limit() {
+        // every box twice as far out as the last one, the sah splits off a few at a time
+        let bounds = (0..100)
+            .map(|i| unit_box(Vec3::X * 2f32.powi(i)))
+            .collect::<Vec<Aabb>>();
+        assert!(check(&bounds, MAX_DEPTH) > 8);
+        assert_eq!(check(&bounds, 8), 8);
+    }
+
+    #[test]
+    fn instances_in_the_same_place() {
+        // nothing to split, so one leaf with all of them
+        let bounds = vec![unit_box(Vec3::ZERO); 100];
+        assert_eq!(check(&bounds, MAX_DEPTH), 0);
+        assert_eq!(build(&bounds).nodes.len(), 1);
+    }
+}
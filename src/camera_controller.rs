@@ -0,0 +1,90 @@
+use renderer::CameraPose;
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+use winit::event::{ElementState, MouseButton};
+use winit::keyboard::KeyCode;
+
+// keep a little away from straight up and down so forward never lines up with the y axis
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+/*
+ * fly camera
+ * WASD moves in the direction the camera looks, space and shift move up and down
+ * dragging with the left mouse button looks around
+ */
+pub struct CameraController {
+    // units per second
+    speed: f32,
+    // radians per pixel of mouse movement
+    sensitivity: f32,
+    forward: bool,
+    backward: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+    looking: bool,
+    mouse_dx: f32,
+    mouse_dy: f32,
+}
+
+impl CameraController {
+    pub fn new(speed: f32, sensitivity: f32) -> Self {
+        CameraController {
+            speed,
+            sensitivity,
+            forward: false,
+            backward: false,
+            left: false,
+            right: false,
+            up: false,
+            down: false,
+            looking: false,
+            mouse_dx: 0.0,
+            mouse_dy: 0.0,
+        }
+    }
+
+    pub fn process_keyboard(&mut self, key: KeyCode, state: ElementState) {
+        let pressed = state == ElementState::Pressed;
+        match key {
+            KeyCode::KeyW | KeyCode::ArrowUp => self.forward = pressed,
+            KeyCode::KeyS | KeyCode::ArrowDown => self.backward = pressed,
+            KeyCode::KeyA | KeyCode::ArrowLeft => self.left = pressed,
+            KeyCode::KeyD | KeyCode::ArrowRight => self.right = pressed,
+            KeyCode::Space => self.up = pressed,
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => self.down = pressed,
+            _ => {}
+        }
+    }
+
+    pub fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) {
+        if button == MouseButton::Left {
+            self.looking = state == ElementState::Pressed;
+        }
+    }
+
+    pub fn process_mouse_motion(&mut self, dx: f64, dy: f64) {
+        if self.looking {
+            self.mouse_dx += dx as f32;
+            self.mouse_dy += dy as f32;
+        }
+    }
+
+    // the pose after dt of moving, unchanged if nothing is pressed
+    pub fn update(&mut self, mut pose: CameraPose, dt: Duration) -> CameraPose {
+        let axis = |pos: bool, neg: bool| pos as i32 as f32 - neg as i32 as f32;
+        let step = self.speed * dt.as_secs_f32();
+        pose.position += pose.forward() * axis(self.forward, self.backward) * step;
+        pose.position += pose.right() * axis(self.right, self.left) * step;
+        pose.position.y += axis(self.up, self.down) * step;
+
+        // moving the mouse right turns right, which is towards -x
+        pose.yaw -= self.mouse_dx * self.sensitivity;
+        pose.pitch = (pose.pitch + self.mouse_dy * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+        self.mouse_dx = 0.0;
+        self.mouse_dy = 0.0;
+
+        pose
+    }
+}
@@ -2,6 +2,12 @@ struct PtInfo {
     width: u32,
     height: u32,
     samples_per_pixel: u32,
+    max_bounces: u32,
+    seed: u32,
+    sampler_type: u32, // SOBOL_SAMPLER, RANDOM_SAMPLER or BLUE_NOISE_SAMPLER
+    restarts: u32, // how often the accumulation started over
+    error_threshold: f32, // 0 samples every pixel forever, see converge
+    roulette_depth: u32, // bounces before russian roulette starts
 }
 
 @group(0) @binding(0)
@@ -9,23 +15,56 @@ var<storage, read_write> pt: array<vec4f>;
 @group(0) @binding(1)
 var<uniform> pt_info: PtInfo;
 
+struct Convergence {
+    unconverged: atomic<u32>, // pixels that still take samples
+    // per pixel the luminance squared summed over its samples, and 1 once it is done
+    pixels: array<vec2f>,
+}
+@group(0) @binding(2)
+var<storage, read_write> convergence: Convergence;
+
 struct Vertex {
     pos: vec3f,
+    u: f32, // texture coordinate, v goes down the image
+    normal: vec3f, // zero if the obj file has none
+    v: f32,
+    color: vec3f, // multiplies the diffuse color
 }
 
 struct MeshInfo {
     vertex_offset: u32,
     index_offset: u32,
+    bvh_root: u32,
+    material: u32,
 }
 
+// roughness is the ggx alpha, 0 would be a perfect mirror
 struct Colors {
     ambient_color: vec3f,
+    ior: f32,
     diffuse_color: vec3f,
+    transmission: f32,
+    specular_color: vec3f,
+    roughness: f32,
+    transmission_color: vec3f,
+    // indices into texture_rects or NO_TEXTURE, the color maps multiply their color
+    diffuse_texture: u32,
+    ambient_texture: u32,
+    specular_texture: u32,
+    bump_texture: u32, // a height map
+    bump_scale: f32, // how far white is above black
 }
 
+const NO_TEXTURE: u32 = 0xffffffffu;
+
 struct ComputeInfo {
     num_meshes: u32,
     num_lights: u32,
+    env_width: u32, // 0 if there is no environment map
+    env_height: u32,
+    env_rotation: f32,
+    env_intensity: f32,
+    num_punctual_lights: u32,
 }
 
 @group(1) @binding(0)
@@ -39,6 +78,115 @@ var<storage> colors: array<Colors>;
 @group(1) @binding(4)
 var<uniform> compute_info: ComputeInfo;
 
+// interior nodes have count 0 and their children at left_or_first and left_or_first + 1
+// leaves have count primitives starting at left_or_first,
+// triangles in blas_triangles for the blas and instances for the tlas
+struct BvhNode {
+    aabb_min: vec3f,
+    left_or_first: u32,
+    aabb_max: vec3f,
+    count: u32,
+}
+
+// indices are absolute, the vertex offset of the mesh is already added
+struct BvhTriangle {
+    indices: vec3u,
+}
+
+// the transforms are the top three rows of a 4x4 matrix, use transform_point and transform_dir
+struct Instance {
+    object_to_world: mat3x4f,
+    world_to_object: mat3x4f,
+    mesh: u32,
+    material: u32,
+    first_light: u32,
+}
+
+const NO_MATERIAL: u32 = 0xffffffffu;
+const NO_LIGHT: u32 = 0xffffffffu;
+
+@group(1) @binding(5)
+var<storage> blas_nodes: array<BvhNode>;
+@group(1) @binding(6)
+var<storage> blas_triangles: array<BvhTriangle>;
+@group(1) @binding(7)
+var<storage> tlas_nodes: array<BvhNode>;
+@group(1) @binding(8)
+var<storage> instances: array<Instance>;
+
+// an emissive triangle in world space, see light.rs
+struct Light {
+    v0: vec3f,
+    leaf: u32,
+    v1: vec3f,
+    power: f32,
+    v2: vec3f,
+    material: u32,
+    triangle: u32, // index into blas_triangles
+}
+
+// a bvh node with the bounding cone of the light normals and the total power below it
+struct LightNode {
+    aabb_min: vec3f,
+    left_or_first: u32,
+    aabb_max: vec3f,
+    count: u32,
+    axis: vec3f,
+    cos_theta_o: f32,
+    power: f32,
+    parent: u32,
+}
+
+@group(1) @binding(9)
+var<storage> lights: array<Light>;
+@group(1) @binding(10)
+var<storage> light_nodes: array<LightNode>;
+// from the first_light of an instance plus the triangle index to the slot in lights
+@group(1) @binding(11)
+var<storage> light_slots: array<u32>;
+// the slot of a triangle that emits nothing, it is not in the light tree
+const NO_SLOT: u32 = 0xffffffffu;
+
+// equirectangular, rgb is radiance and a the pdf of picking the pixel, see environment.rs
+@group(1) @binding(12)
+var<storage> env_pixels: array<vec4f>;
+// the cdf of every row, then the cdf over the rows
+@group(1) @binding(13)
+var<storage> env_cdf: array<f32>;
+
+// the preetham daylight model, see sky.rs
+struct Sky {
+    a: vec3f, // perez coefficients for luminance, x and y
+    enabled: u32,
+    b: vec3f,
+    cos_sun: f32,
+    c: vec3f,
+    d: vec3f,
+    e: vec3f,
+    zenith: vec3f, // luminance, x and y straight up over the perez function there
+    sun_direction: vec3f, // towards the sun
+    sun_radiance: vec3f,
+    ground_radiance: vec3f,
+}
+
+@group(1) @binding(14)
+var<uniform> sky: Sky;
+
+// every texture of the scene packed into the layers of an array at its own size, see texture.rs
+@group(1) @binding(15)
+var textures: texture_2d_array<f32>;
+
+const MAX_TEXTURES: u32 = 1024u;
+
+struct TextureRect {
+    origin: vec2u, // the top left texel in its layer
+    size: vec2u,
+    @size(16) layer: u32,
+}
+
+@group(1) @binding(16)
+var<uniform> texture_rects: array<TextureRect, MAX_TEXTURES>;
+
 struct Camera {
     position: vec3f,
     yaw: f32,
@@ -53,6 +201,31 @@ struct Camera {
 @group(2) @binding(0)
 var<uniform> camera: Camera;
 
+// 32 direction numbers for every dimension of the sobol sequence, see sobol.rs
+const SOBOL_DIMENSIONS: u32 = 53u;
+@group(2) @binding(1)
+var<uniform> sobol_directions: array<vec4u, 424>; // SOBOL_DIMENSIONS * 32 / 4
+// a tileable mask with every value from 0 to 1 once, see blue_noise.rs
+@group(2) @binding(2)
+var blue_noise: texture_2d<f32>;
+
+// a light without geometry, see punctual.rs
+struct PunctualLight {
+    position: vec3f,
+    kind: u32,
+    direction: vec3f, // where the light goes
+    cos_outer: f32, // spot cone, or angular radius of a directional light
+    intensity: vec3f,
+    cos_inner: f32, // where the spot starts to fall off
+}
+
+const POINT_LIGHT: u32 = 0u;
+const SPOT_LIGHT: u32 = 1u;
+const DIRECTIONAL_LIGHT: u32 = 2u;
+
+@group(3) @binding(0)
+var<storage> punctual_lights: array<PunctualLight>;
+
 // The cornell scene is on the order of 500 units
 const EPSILON: f32 = 0.001;
 
@@ -66,157 +239,1149 @@ fn pcg(n: u32) -> u32 {
     return (h >> 22u) ^ h;
 }
 
+/*
+ * a hash that mixes all three inputs into every output, for seeds from coordinates
+ * https://jcgt.org/published/0009/03/02/
+ */
+fn pcg3d(v_in: vec3u) -> vec3u {
+    var v = v_in * 1664525u + 1013904223u;
+    v.x += v.y * v.z;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    v ^= v >> vec3u(16u);
+    v.x += v.y * v.z;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    return v;
+}
+
 fn rand() -> f32 {
     seed = pcg(seed);
 
     return f32(seed)/f32(0xffffffffu);
 }
 
+const SOBOL_SAMPLER: u32 = 0u;
+const RANDOM_SAMPLER: u32 = 1u;
+const BLUE_NOISE_SAMPLER: u32 = 2u;
+
+/*
+ * every random decision of a sample has its own dimension of the sampler,
+ * so the sobol sequence can stratify each of them over the samples of a pixel
+ * the camera takes the first two, then every bounce takes DIMENSIONS_PER_BOUNCE
+ * starting at bounce_dimension, the DIM_ constants are offsets into those
+ */
+const CAMERA_DIMENSIONS: u32 = 2u;
+const DIMENSIONS_PER_BOUNCE: u32 = 11u;
+const DIM_TRANSMISSION: u32 = 0u; // glass or the other lobes
+const DIM_FRESNEL: u32 = 1u; // reflect or refract
+const DIM_LIGHT_STRATEGY: u32 = 2u; // environment, triangles or punctual lights
+const DIM_LIGHT_PICK: u32 = 3u; // which light, or the row of the environment
+const DIM_LIGHT_POINT: u32 = 4u; // 2d, where on the light
+const DIM_LIGHT_COLUMN: u32 = 6u; // the column of the environment
+const DIM_LOBE: u32 = 7u; // diffuse or specular
+const DIM_BOUNCE: u32 = 8u; // 2d, the direction of the next ray
+const DIM_ROULETTE: u32 = 10u; // whether the path goes on
+
+// the biggest float below 1
+const ONE_MINUS_EPSILON: f32 = 0.99999994;
+
+var<private> sample_index: u32;
+// decorrelates the pixels, the same for every sample of a pixel
+var<private> pixel_seed: u32;
+// what owen scrambles the sobol sequence with, shared by all pixels for blue noise
+var<private> scramble_seed: u32;
+var<private> pixel: vec2u;
+var<private> bounce_dimension: u32;
+
+// https://www.jcgt.org/published/0009/04/01/
+fn laine_karras_permutation(x_in: u32, seed: u32) -> u32 {
+    var x = x_in + seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+// owen scrambling, a random permutation of every level of binary intervals
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    return seed ^ (v + (seed << 6u) + (seed >> 2u));
+}
+
+fn sobol(index: u32, dim: u32) -> u32 {
+    var x = 0u;
+    var i = index;
+    var k = dim * 32u;
+    while (i != 0u) {
+        if ((i & 1u) != 0u) {
+            x ^= sobol_directions[k / 4u][k % 4u];
+        }
+        i >>= 1u;
+        k++;
+    }
+    return x;
+}
+
+/*
+ * the sample_index-th point of the sequence in one dimension
+ * the index is shuffled per pixel and every dimension owen scrambled, which keeps
+ * the stratification but makes neighbouring pixels independent
+ * past the table the dimensions come around again with a differently shuffled index
+ */
+fn sample_1d(dim: u32) -> f32 {
+    if (pt_info.sampler_type == RANDOM_SAMPLER) {
+        return rand();
+    }
+    let round = dim / SOBOL_DIMENSIONS;
+    let index = nested_uniform_scramble(sample_index, pcg(hash_combine(scramble_seed, round)));
+    let x = nested_uniform_scramble(sobol(index, dim % SOBOL_DIMENSIONS), pcg(hash_combine(scramble_seed, dim)));
+    // 24 bits are all a float below 1 can hold
+    let u = f32(x >> 8u) / 16777216.0;
+    if (pt_info.sampler_type == BLUE_NOISE_SAMPLER) {
+        return min(fract(u + blue_noise_offset(dim)), ONE_MINUS_EPSILON);
+    }
+    return u;
+}
+
+/*
+ * the blue noise sampler gives every pixel the same sequence, shifted by the mask
+ * (a cranley patterson rotation), so neighbours err in different directions
+ * every dimension reads the mask moved by a different r2 step so they don't line up,
+ * and every restart adds the golden ratio so a moving camera cycles through all shifts
+ */
+fn blue_noise_offset(dim: u32) -> f32 {
+    let size = textureDimensions(blue_noise);
+    let shift = vec2u(fract(vec2f(0.5) + f32(dim) * vec2f(0.7548777, 0.5698403)) * vec2f(size));
+    let mask = textureLoad(blue_noise, (pixel + shift) % size, 0).r;
+    // the golden ratio in 32 bit fixed point, so it doesn't lose precision over many restarts
+    let golden = f32((pt_info.restarts * 0x9e3779b9u) >> 8u) / 16777216.0;
+    return fract(mask + golden);
+}
+
+fn sample_2d(dim: u32) -> vec2f {
+    return vec2f(sample_1d(dim), sample_1d(dim + 1u));
+}
+
+// a dimension of the current bounce, offset is one of the DIM_ constants
+fn bounce_sample(offset: u32) -> f32 {
+    return sample_1d(bounce_dimension + offset);
+}
+
+fn bounce_sample_2d(offset: u32) -> vec2f {
+    return sample_2d(bounce_dimension + offset);
+}
+
 struct Collision {
     distance: f32,
     position: vec3f,
     normal: vec3f,
     color_idx: u32,
+    light: u32, // first_light of the instance plus the triangle, NO_LIGHT if it does not emit
+    instance: u32,
+    triangle: u32, // index into blas_triangles
+    barycentric: vec2f, // of the second and third vertex
 }
 
 const PI: f32 = 3.14159265358979323846264338327950288;
 
-fn apply_lighting(pos: vec3f, nor: vec3f) -> vec3f {
-    var color = vec3f(0.0);
-    var lights = 0;
-    for (var i = 0; i < i32(arrayLength(&mesh_info)) - 1; i++) { // for each mesh
-        let light_color = colors[i].ambient_color;
-        if (light_color.r > 0.0 || light_color.g > 0.0 || light_color.b > 0.0) { // if it is a light
-            let vertex_offset = mesh_info[i].vertex_offset;
-            let index_offset = mesh_info[i].index_offset;
-            let index_end = mesh_info[i+1].index_offset;
-            for (var j: i32 = i32(index_offset); j < i32(index_end); j += 3) { // for every triangle in that light
-                let v0: vec3f = vertices[vertex_offset + indices[j]].pos;
-                let v1: vec3f = vertices[vertex_offset + indices[j+1]].pos;
-                let v2: vec3f = vertices[vertex_offset + indices[j+2]].pos;
-
-                // pick a random light point in the triangle
-
-                // barycentric coordinates for homogenous probability over the surface
-                // https://people.cs.kuleuven.be/~philip.dutre/GI/TotalCompendium.pdf
-                let r1 = rand();
-                let r2 = rand();
-                let alpha = 1.0 - sqrt(r1);
-                let beta = (1.0 - r2)*sqrt(r1);
-                let gamma = r2*sqrt(r1);
-                let point = alpha*v0 + beta*v1 + gamma*v2;
-
-                // see if object point is illumineted by the light point
-                let dir = point - pos;
-
-                let inters = closest_intersection(pos, dir);
-
-                // if object is illuminated
-                if (inters.distance >= length(dir) - EPSILON) {
-                    // calculate lighting
-                    var add = light_color * 10000.0 * max(dot(nor,normalize(dir)), 0.0); //magic param, why am i multiplying by SO MUCH. Something must be wrong
-                    add /= (4.0*pow(length(dir), 2.0));
-                    color += add;
-                    lights += 1;
+fn transform_point(m: mat3x4f, p: vec3f) -> vec3f {
+    return vec4f(p, 1.0) * m;
+}
+
+fn transform_dir(m: mat3x4f, d: vec3f) -> vec3f {
+    return vec4f(d, 0.0) * m;
+}
+
+// normals need the inverse transpose, which is the transpose of world_to_object
+fn transform_normal(inst: Instance, n: vec3f) -> vec3f {
+    return n.x*inst.world_to_object[0].xyz + n.y*inst.world_to_object[1].xyz + n.z*inst.world_to_object[2].xyz;
+}
+
+fn instance_material(inst: Instance) -> u32 {
+    if (inst.material == NO_MATERIAL) {
+        return mesh_info[inst.mesh].material;
+    }
+    return inst.material;
+}
+
+/*
+ * multiple importance sampling weight for a sample taken with pdf a
+ * when the same direction could also have come from a strategy with pdf b
+ * https://graphics.stanford.edu/papers/veach_thesis/chapter9.pdf
+ */
+fn power_heuristic(a: f32, b: f32) -> f32 {
+    let a2 = a * a;
+    return a2 / (a2 + b * b);
+}
+
+// cos(a - b) and sin(a - b) of two angles given by their sin and cos, 0 if b is bigger than a
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if (cos_a > cos_b) {
+        return 1.0;
+    }
+    return cos_a * cos_b + sin_a * sin_b;
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if (cos_a > cos_b) {
+        return 0.0;
+    }
+    return sin_a * cos_b - cos_a * sin_b;
+}
+
+/*
+ * an upper bound of how much light a node of the light tree can send to a point p with normal n
+ * the box is bounded by a sphere, from p the sphere covers a cone of directions
+ * the smallest angle between those and the normals of the lights gives the best case emitter cosine,
+ * the smallest angle to n the best case receiver cosine
+ * https://pbr-book.org/4ed/Light_Sources/Light_Sampling#BVHLightSampling
+ */
+fn light_importance(node: LightNode, p: vec3f, n: vec3f) -> f32 {
+    if (node.power <= 0.0) {
+        return 0.0;
+    }
+    let center = 0.5 * (node.aabb_min + node.aabb_max);
+    let radius2 = 0.25 * dot(node.aabb_max - node.aabb_min, node.aabb_max - node.aabb_min);
+    let to_p = p - center;
+    let d2 = dot(to_p, to_p);
+    let wi = select(n, to_p / sqrt(d2), d2 > 0.0);
+
+    // two sided, so whichever of the axis and its opposite is closer
+    let cos_w = abs(dot(node.axis, wi));
+    let sin_w = sqrt(max(0.0, 1.0 - cos_w * cos_w));
+
+    // everything is in view if p is inside the sphere
+    var cos_b = -1.0;
+    if (d2 > radius2) {
+        cos_b = sqrt(max(0.0, 1.0 - radius2 / d2));
+    }
+    let sin_b = sqrt(max(0.0, 1.0 - cos_b * cos_b));
+
+    let cos_o = node.cos_theta_o;
+    let sin_o = sqrt(max(0.0, 1.0 - cos_o * cos_o));
+    let cos_x = cos_sub_clamped(sin_w, cos_w, sin_o, cos_o);
+    let sin_x = sin_sub_clamped(sin_w, cos_w, sin_o, cos_o);
+    let cos_l = cos_sub_clamped(sin_x, cos_x, sin_b, cos_b);
+    // area lights do not send anything sideways
+    if (cos_l <= 0.0) {
+        return 0.0;
+    }
+
+    let cos_i = abs(dot(wi, n));
+    let sin_i = sqrt(max(0.0, 1.0 - cos_i * cos_i));
+    let cos_r = cos_sub_clamped(sin_i, cos_i, sin_b, cos_b);
+    return max(0.0, node.power * cos_l * cos_r / max(d2, radius2));
+}
+
+struct LightPick {
+    slot: u32,
+    // probability of picking this light, 0 if there was nothing to pick
+    pmf: f32,
+}
+
+/*
+ * walk down the light tree, at every node go left or right in proportion to their importance
+ * in the leaf pick one of the lights by power
+ * one random number does it all, after every choice it is stretched back over [0, 1)
+ */
+fn pick_light(p: vec3f, n: vec3f) -> LightPick {
+    var pick: LightPick;
+    pick.pmf = 0.0;
+    if (compute_info.num_lights == 0u) {
+        return pick;
+    }
+    pick.pmf = 1.0;
+    var u = bounce_sample(DIM_LIGHT_PICK);
+    var node = light_nodes[0];
+    loop {
+        if (node.count > 0u) {
+            if (!(node.power > 0.0)) {
+                pick.pmf = 0.0;
+                return pick;
+            }
+            let r = u * node.power;
+            var sum = 0.0;
+            pick.slot = node.left_or_first + node.count - 1u;
+            for (var i = node.left_or_first; i < node.left_or_first + node.count; i++) {
+                sum += lights[i].power;
+                if (r < sum) {
+                    pick.slot = i;
+                    break;
                 }
             }
+            pick.pmf *= lights[pick.slot].power / node.power;
+            return pick;
+        }
+        let left = light_nodes[node.left_or_first];
+        let right = light_nodes[node.left_or_first + 1u];
+        let importance_left = light_importance(left, p, n);
+        let importance_right = light_importance(right, p, n);
+        // nothing here can light p, or the bound broke down, either way no light sample
+        if (!(importance_left + importance_right > 0.0)) {
+            pick.pmf = 0.0;
+            return pick;
+        }
+        let p_left = importance_left / (importance_left + importance_right);
+        if (u < p_left) {
+            u = min(u / p_left, ONE_MINUS_EPSILON);
+            node = left;
+            pick.pmf *= p_left;
+        } else {
+            u = min((u - p_left) / (1.0 - p_left), ONE_MINUS_EPSILON);
+            node = right;
+            pick.pmf *= 1.0 - p_left;
+        }
+    }
+    return pick;
+}
+
+/*
+ * probability of pick_light picking the light in slot, found by walking up from its leaf
+ * 0 for triangles left out of the tree and wherever pick_light would give up
+ */
+fn light_pmf(slot: u32, p: vec3f, n: vec3f) -> f32 {
+    if (slot == NO_SLOT) {
+        return 0.0;
+    }
+    var idx = lights[slot].leaf;
+    var pmf = lights[slot].power / light_nodes[idx].power;
+    while (idx != 0u) {
+        let parent = light_nodes[idx].parent;
+        let left = light_nodes[parent].left_or_first;
+        let sibling = select(left, left + 1u, idx == left);
+        let importance = light_importance(light_nodes[idx], p, n);
+        let importance_sibling = light_importance(light_nodes[sibling], p, n);
+        if (!(importance > 0.0)) {
+            return 0.0;
+        }
+        pmf *= importance / (importance + importance_sibling);
+        idx = parent;
+    }
+    return pmf;
+}
+
+/*
+ * solid angle pdf of picking a point on a light triangle
+ * the triangle is picked with probability pmf and then a point on it with 1 / area
+ * cos_l is the cosine at the light, dist the distance to it
+ */
+fn light_pdf(light: Light, pmf: f32, dist: f32, cos_l: f32) -> f32 {
+    if (pmf <= 0.0) {
+        return 0.0;
+    }
+    let area = 0.5 * length(cross(light.v1 - light.v0, light.v2 - light.v0));
+    return pmf * dist * dist / (cos_l * area);
+}
+
+// shown when there is no environment map, it does not light the scene
+const SKY_COLOR: vec3f = vec3f(0.0, 0.1, 0.5);
+
+// chances that a light sample goes to the environment, the triangles or the punctual lights
+fn light_strategies() -> vec3f {
+    let present = vec3f(
+        select(0.0, 1.0, compute_info.env_width > 0u),
+        select(0.0, 1.0, compute_info.num_lights > 0u),
+        select(0.0, 1.0, compute_info.num_punctual_lights > 0u),
+    );
+    let count = present.x + present.y + present.z;
+    if (count == 0.0) {
+        return vec3f(0.0);
+    }
+    return present / count;
+}
+
+// turn around the up axis
+fn rotate_y(v: vec3f, angle: f32) -> vec3f {
+    let c = cos(angle);
+    let s = sin(angle);
+    return vec3f(c * v.x + s * v.z, v.y, -s * v.x + c * v.z);
+}
+
+// from a direction to where it is in the environment map, both in [0, 1]
+fn environment_uv(dir: vec3f) -> vec2f {
+    let d = rotate_y(normalize(dir), -compute_info.env_rotation);
+    let u = atan2(d.z, d.x) / (2.0 * PI) + 0.5;
+    let v = acos(clamp(d.y, -1.0, 1.0)) / PI;
+    return vec2f(u, v);
+}
+
+fn environment_direction(uv: vec2f) -> vec3f {
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let theta = uv.y * PI;
+    let d = vec3f(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+    return rotate_y(d, compute_info.env_rotation);
+}
+
+fn environment_pixel(uv: vec2f) -> vec4f {
+    let w = compute_info.env_width;
+    let h = compute_info.env_height;
+    let x = min(u32(uv.x * f32(w)), w - 1u);
+    let y = min(u32(uv.y * f32(h)), h - 1u);
+    return env_pixels[y * w + x];
+}
+
+fn environment_radiance(dir: vec3f) -> vec3f {
+    return environment_pixel(environment_uv(dir)).rgb * compute_info.env_intensity;
+}
+
+// the image is stretched over the sphere, the jacobian of that is 2 pi^2 sin(theta)
+fn environment_pdf(dir: vec3f) -> f32 {
+    let uv = environment_uv(dir);
+    let sin_theta = sin(uv.y * PI);
+    if (sin_theta <= 0.0) {
+        return 0.0;
+    }
+    return environment_pixel(uv).a / (2.0 * PI * PI * sin_theta);
+}
+
+// radiance of the sky, the ground below the horizon, the sun disk is only seen if with_sun
+fn sky_radiance(dir: vec3f, with_sun: bool) -> vec3f {
+    let d = normalize(dir);
+    if (d.y < 0.0) {
+        return sky.ground_radiance;
+    }
+    let cos_gamma = clamp(dot(d, sky.sun_direction), -1.0, 1.0);
+    if (with_sun && cos_gamma >= sky.cos_sun) {
+        return sky.sun_radiance;
+    }
+    let gamma = acos(cos_gamma);
+    let perez = (1.0 + sky.a * exp(sky.b / max(d.y, 0.001)))
+        * (1.0 + sky.c * exp(sky.d * gamma) + sky.e * cos_gamma * cos_gamma);
+    let xyy = sky.zenith * perez;
+    if (xyy.z <= 0.0) {
+        return vec3f(0.0);
+    }
+    let xyz = vec3f(xyy.y * xyy.x / xyy.z, xyy.x, (1.0 - xyy.y - xyy.z) * xyy.x / xyy.z);
+    let rgb = vec3f(
+        dot(vec3f(3.2406, -1.5372, -0.4986), xyz),
+        dot(vec3f(-0.9689, 1.8758, 0.0415), xyz),
+        dot(vec3f(0.0557, -0.2040, 1.0570), xyz),
+    );
+    return max(rgb, vec3f(0.0));
+}
+
+// first entry of a cdf in env_cdf that is bigger than u
+fn find_interval(offset: u32, count: u32, u: f32) -> u32 {
+    var lo = 0u;
+    var hi = count - 1u;
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        if (env_cdf[offset + mid] > u) {
+            hi = mid;
+        } else {
+            lo = mid + 1u;
+        }
+    }
+    return lo;
+}
+
+// pick a row, then a pixel in it, then a point in the pixel
+fn sample_environment() -> vec3f {
+    let w = compute_info.env_width;
+    let h = compute_info.env_height;
+    let y = find_interval(w * h, h, bounce_sample(DIM_LIGHT_PICK));
+    let x = find_interval(y * w, w, bounce_sample(DIM_LIGHT_COLUMN));
+    let jitter = bounce_sample_2d(DIM_LIGHT_POINT);
+    let uv = vec2f((f32(x) + jitter.x) / f32(w), (f32(y) + jitter.y) / f32(h));
+    return environment_direction(uv);
+}
+
+/*
+ * direct light from the environment, the triangles or the punctual lights, with one shadow ray
+ * emitters are two sided, ambient_color is the radiance they give off
+ * bounce rays that hit a light or leave the scene also count it,
+ * both are weighted with the power heuristic
+ * punctual lights can not be hit, so they are only found here
+ * wo points away from the surface, towards where the light ends up
+ * nor is the shading normal, geo the geometric one on the side of wo,
+ * light from behind the actual surface can not reach it whatever nor says
+ */
+fn apply_lighting(pos: vec3f, nor: vec3f, geo: vec3f, wo: vec3f, mat: Colors) -> vec3f {
+    let p = light_strategies();
+    if (p.x + p.y + p.z <= 0.0) {
+        return vec3f(0.0);
+    }
+    let r = bounce_sample(DIM_LIGHT_STRATEGY);
+    if (r < p.x) {
+        return environment_lighting(pos, nor, geo, wo, mat, p.x);
+    }
+    if (r < p.x + p.y) {
+        return triangle_lighting(pos, nor, geo, wo, mat, p.y);
+    }
+    return punctual_lighting(pos, nor, geo, wo, mat, p.z);
+}
+
+fn spot_falloff(light: PunctualLight, cos_theta: f32) -> f32 {
+    if (cos_theta >= light.cos_inner) {
+        return 1.0;
+    }
+    if (cos_theta <= light.cos_outer) {
+        return 0.0;
+    }
+    return smoothstep(light.cos_outer, light.cos_inner, cos_theta);
+}
+
+// one punctual light picked uniformly, p_pick is the chance we sample them at all
+fn punctual_lighting(pos: vec3f, nor: vec3f, geo: vec3f, wo: vec3f, mat: Colors, p_pick: f32) -> vec3f {
+    let n = compute_info.num_punctual_lights;
+    let light = punctual_lights[min(u32(bounce_sample(DIM_LIGHT_PICK) * f32(n)), n - 1u)];
+
+    var wi: vec3f;
+    // incoming radiance over the pdf of wi
+    var li: vec3f;
+    // distance to the light, or -1 if it is infinitely far away
+    var dist = -1.0;
+    if (light.kind == DIRECTIONAL_LIGHT) {
+        if (light.cos_outer >= 1.0) {
+            wi = -light.direction;
+            li = light.intensity;
+        } else {
+            // a uniform point on the disk of the sun, its radiance is irradiance / (pi sin^2)
+            // and the pdf 1 / (2 pi (1 - cos))
+            let u = bounce_sample_2d(DIM_LIGHT_POINT);
+            let cos_t = 1.0 - u.x * (1.0 - light.cos_outer);
+            let sin_t = sqrt(max(0.0, 1.0 - cos_t * cos_t));
+            let phi = 2.0 * PI * u.y;
+            wi = orthonormal_basis(-light.direction) * vec3f(sin_t * cos(phi), sin_t * sin(phi), cos_t);
+            let sin2_r = 1.0 - light.cos_outer * light.cos_outer;
+            li = light.intensity * 2.0 * (1.0 - light.cos_outer) / sin2_r;
         }
+    } else {
+        let dir = light.position - pos;
+        dist = length(dir);
+        wi = dir / dist;
+        li = light.intensity / (dist * dist);
+        if (light.kind == SPOT_LIGHT) {
+            li *= spot_falloff(light, dot(-wi, light.direction));
+        }
+    }
+
+    let f = eval_bsdf(mat, nor, wo, wi);
+    if (all(f == vec3f(0.0)) || all(li == vec3f(0.0)) || dot(wi, geo) <= 0.0) {
+        return vec3f(0.0);
+    }
+    let inters = closest_intersection(pos, wi);
+    if (inters.distance >= 0.0 && (dist < 0.0 || inters.distance < dist - EPSILON)) {
+        return vec3f(0.0);
     }
-    if (lights > 0) {
-        return color/f32(lights);
+    return li * f * f32(n) / p_pick;
+}
+
+// p_pick is the chance we sample the environment at all
+fn environment_lighting(pos: vec3f, nor: vec3f, geo: vec3f, wo: vec3f, mat: Colors, p_pick: f32) -> vec3f {
+    let wi = sample_environment();
+    let pdf = p_pick * environment_pdf(wi);
+    let f = eval_bsdf(mat, nor, wo, wi);
+    if (pdf <= 0.0 || all(f == vec3f(0.0)) || dot(wi, geo) <= 0.0) {
+        return vec3f(0.0);
+    }
+    if (closest_intersection(pos, wi).distance >= 0.0) {
+        return vec3f(0.0);
     }
-    return vec3f(0.0);
+    let weight = power_heuristic(pdf, bsdf_pdf(mat, nor, wo, wi));
+    return environment_radiance(wi) * f * weight / pdf;
 }
 
+/*
+ * one emissive triangle picked with the light tree, the point on it is picked uniformly
+ * p_pick is the chance we sample the triangles at all
+ */
+fn triangle_lighting(pos: vec3f, nor: vec3f, geo: vec3f, wo: vec3f, mat: Colors, p_pick: f32) -> vec3f {
+    let pick = pick_light(pos, nor);
+    if (pick.pmf <= 0.0) {
+        return vec3f(0.0);
+    }
+    let light = lights[pick.slot];
+    let mat_l = colors[light.material];
+
+    // barycentric coordinates for homogenous probability over the surface
+    // https://people.cs.kuleuven.be/~philip.dutre/GI/TotalCompendium.pdf
+    let u = bounce_sample_2d(DIM_LIGHT_POINT);
+    let r1 = u.x;
+    let r2 = u.y;
+    let alpha = 1.0 - sqrt(r1);
+    let beta = (1.0 - r2)*sqrt(r1);
+    let gamma = r2*sqrt(r1);
+    let point = alpha*light.v0 + beta*light.v1 + gamma*light.v2;
+    var emission = mat_l.ambient_color;
+    if (mat_l.ambient_texture != NO_TEXTURE) {
+        emission *= texture_color(mat_l.ambient_texture, texcoord(light.triangle, vec2f(beta, gamma)));
+    }
+
+    let dir = point - pos;
+    let dist = length(dir);
+    let wi = dir / dist;
+    let f = eval_bsdf(mat, nor, wo, wi);
+    let cos_l = abs(dot(normalize(cross(light.v1 - light.v0, light.v2 - light.v0)), wi));
+    if (all(f == vec3f(0.0)) || cos_l <= 0.0 || dot(wi, geo) <= 0.0) {
+        return vec3f(0.0);
+    }
+
+    // see if object point is illumineted by the light point
+    let inters = closest_intersection(pos, dir);
+    if (inters.distance < dist - EPSILON) {
+        return vec3f(0.0);
+    }
+    let pdf = light_pdf(light, p_pick * pick.pmf, dist, cos_l);
+    let weight = power_heuristic(pdf, bsdf_pdf(mat, nor, wo, wi));
+    return emission * f * weight / pdf;
+}
+
+// the builder never goes deeper than this, see MAX_DEPTH in bvh.rs
+const BVH_STACK_SIZE: u32 = 64u;
+
+// returns the ray parameter where the ray enters the box, or max_t if it misses
+fn intersect_aabb(ro: vec3f, inv_rd: vec3f, aabb_min: vec3f, aabb_max: vec3f, max_t: f32) -> f32 {
+    let t0 = (aabb_min - ro) * inv_rd;
+    let t1 = (aabb_max - ro) * inv_rd;
+    let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+    if (t_far >= max(t_near, 0.0) && t_near < max_t) {
+        return t_near;
+    }
+    return max_t;
+}
+
+struct MeshHit {
+    t: f32,
+    normal: vec3f, // in object space, not normalized
+    triangle: u32, // index into blas_triangles
+    barycentric: vec2f,
+}
+
+/*
+ * closest hit with one mesh, ro and rd are in the object space of the mesh
+ * t is the same in object and world space since the transforms are affine
+ * rd_len is the length of rd in world space, to reject hits that are too close
+ */
+fn intersect_mesh(mesh: u32, ro: vec3f, rd: vec3f, rd_len: f32, max_t: f32) -> MeshHit {
+    var hit: MeshHit;
+    hit.t = max_t;
+    let inv_rd = 1.0 / rd;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0u;
+    var node_idx = mesh_info[mesh].bvh_root;
+    loop {
+        let node = blas_nodes[node_idx];
+        if (node.count > 0u) { // leaf, test every triangle in it
+            for (var j = node.left_or_first; j < node.left_or_first + node.count; j++) {
+                // https://iquilezles.org/articles/intersectors/
+                let tri = blas_triangles[j];
+                let v0: vec3f = vertices[tri.indices.x].pos;
+                let v1: vec3f = vertices[tri.indices.y].pos;
+                let v2: vec3f = vertices[tri.indices.z].pos;
+
+                let e1 = v1 - v0;
+                let e2 = v2 - v0;
+                let b = ro - v0;
+
+                let n = cross(e1, e2);
+                let q = cross(b, rd);
+
+                let d = 1.0/dot(rd, n);
+                let u = d*dot(-q, e2);
+                let v = d*dot(q, e1);
+                let t = d*dot(-n, b);
+
+                // if the intersection is within the triangle and not super close
+                if (u >= 0.0 && v >= 0.0 && u + v <= 1.0 && rd_len*t > EPSILON && t < hit.t) {
+                    hit.t = t;
+                    hit.normal = n;
+                    hit.triangle = j;
+                    hit.barycentric = vec2f(u, v);
+                }
+            }
+        } else { // interior, visit the closer child first and come back for the other
+            let left = node.left_or_first;
+            let right = left + 1u;
+            let t_left = intersect_aabb(ro, inv_rd, blas_nodes[left].aabb_min, blas_nodes[left].aabb_max, hit.t);
+            let t_right = intersect_aabb(ro, inv_rd, blas_nodes[right].aabb_min, blas_nodes[right].aabb_max, hit.t);
+            let hit_left = t_left < hit.t;
+            let hit_right = t_right < hit.t;
+            if (hit_left && hit_right) {
+                if (t_left <= t_right) {
+                    node_idx = left;
+                    stack[stack_size] = right;
+                } else {
+                    node_idx = right;
+                    stack[stack_size] = left;
+                }
+                stack_size += 1u;
+                continue;
+            } else if (hit_left) {
+                node_idx = left;
+                continue;
+            } else if (hit_right) {
+                node_idx = right;
+                continue;
+            }
+        }
+        if (stack_size == 0u) {
+            break;
+        }
+        stack_size -= 1u;
+        node_idx = stack[stack_size];
+    }
+    return hit;
+}
+
+// walks the tlas and for every instance it reaches, the blas of its mesh
 fn closest_intersection(ro: vec3f, rd: vec3f) -> Collision {
-    var color_idx: i32 = 0;
+    var color_idx: u32 = 0u;
     let max_dist = 1e20f;
-    var distance: f32 = max_dist;
-    var position: vec3f = ro;
+    var best_t: f32 = max_dist;
     var normal: vec3f = rd;
-    for (var i = 0; i < i32(arrayLength(&mesh_info)) - 1; i++) {
-        let vertex_offset = mesh_info[i].vertex_offset;
-        let index_offset = mesh_info[i].index_offset;
-        let index_end = mesh_info[i+1].index_offset;
-        for (var j = i32(index_offset); j < i32(index_end); j += 3) {
-            // https://iquilezles.org/articles/intersectors/
-            let v0: vec3f = vertices[vertex_offset + indices[j]].pos;
-            let v1: vec3f = vertices[vertex_offset + indices[j+1]].pos;
-            let v2: vec3f = vertices[vertex_offset + indices[j+2]].pos;
-
-            let e1 = v1 - v0;
-            let e2 = v2 - v0;
-            let b = ro - v0;
-
-            let n = cross(e1, e2);
-            let q = cross(b, rd);
-
-            let d = 1.0/dot(rd, n);
-            let u = d*dot(-q, e2);
-            let v = d*dot(q, e1);
-            let t = d*dot(-n, b);
-
-            let dist2 = length(rd)*t;
-
-            // if the intersection is within the triangle and not super close
-            if (u >= 0.0 && v >= 0.0 && u + v <= 1.0 && dist2 > EPSILON) {
-                if (dist2 < distance) {
-                    distance = dist2;
-                    color_idx = i;
-                    position = ro + t*rd;
-                    normal = normalize(cross(e1, e2));
+    var hit_inst = 0u;
+    var hit_triangle = 0u;
+    var barycentric = vec2f(0.0);
+    let rd_len = length(rd);
+    let inv_rd = 1.0 / rd;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0u;
+    var node_idx = 0u;
+    // the root is not tested by a parent, so do it here
+    let hit_root = intersect_aabb(ro, inv_rd, tlas_nodes[0].aabb_min, tlas_nodes[0].aabb_max, best_t) < best_t;
+    loop {
+        if (!hit_root) {
+            break;
+        }
+        let node = tlas_nodes[node_idx];
+        if (node.count > 0u) { // leaf, test every instance in it
+            for (var j = node.left_or_first; j < node.left_or_first + node.count; j++) {
+                let inst = instances[j];
+                let hit = intersect_mesh(
+                    inst.mesh,
+                    transform_point(inst.world_to_object, ro),
+                    transform_dir(inst.world_to_object, rd),
+                    rd_len,
+                    best_t,
+                );
+                if (hit.t < best_t) {
+                    best_t = hit.t;
+                    color_idx = instance_material(inst);
+                    normal = normalize(transform_normal(inst, hit.normal));
+                    hit_inst = j;
+                    hit_triangle = hit.triangle;
+                    barycentric = hit.barycentric;
+                }
+            }
+        } else { // interior, visit the closer child first and come back for the other
+            let left = node.left_or_first;
+            let right = left + 1u;
+            let t_left = intersect_aabb(ro, inv_rd, tlas_nodes[left].aabb_min, tlas_nodes[left].aabb_max, best_t);
+            let t_right = intersect_aabb(ro, inv_rd, tlas_nodes[right].aabb_min, tlas_nodes[right].aabb_max, best_t);
+            let hit_left = t_left < best_t;
+            let hit_right = t_right < best_t;
+            if (hit_left && hit_right) {
+                if (t_left <= t_right) {
+                    node_idx = left;
+                    stack[stack_size] = right;
+                } else {
+                    node_idx = right;
+                    stack[stack_size] = left;
                 }
+                stack_size += 1u;
+                continue;
+            } else if (hit_left) {
+                node_idx = left;
+                continue;
+            } else if (hit_right) {
+                node_idx = right;
+                continue;
             }
         }
+        if (stack_size == 0u) {
+            break;
+        }
+        stack_size -= 1u;
+        node_idx = stack[stack_size];
     }
-    if (distance >= max_dist) {
-        distance = -1.0;
-    }
+
     var out: Collision;
-    out.distance = distance;
-    out.position = position;
+    out.distance = -1.0;
+    out.position = ro;
     out.normal = normal;
-    out.color_idx = u32(color_idx);
+    out.color_idx = color_idx;
+    out.light = NO_LIGHT;
+    out.instance = hit_inst;
+    out.triangle = hit_triangle;
+    out.barycentric = barycentric;
+    if (best_t < max_dist) {
+        out.distance = rd_len*best_t;
+        out.position = ro + best_t*rd;
+        let inst = instances[hit_inst];
+        if (inst.first_light != NO_LIGHT) {
+            // the lights of an instance are in the same order as the blas triangles of its mesh
+            out.light = inst.first_light + hit_triangle - mesh_info[inst.mesh].index_offset / 3u;
+        }
+    }
 
     return out;
 }
 
+// the texture coordinate at a point of a blas triangle
+fn texcoord(triangle: u32, barycentric: vec2f) -> vec2f {
+    let idx = blas_triangles[triangle].indices;
+    let uv0 = vec2f(vertices[idx.x].u, vertices[idx.x].v);
+    let uv1 = vec2f(vertices[idx.y].u, vertices[idx.y].v);
+    let uv2 = vec2f(vertices[idx.z].u, vertices[idx.z].v);
+    return (1.0 - barycentric.x - barycentric.y) * uv0 + barycentric.x * uv1 + barycentric.y * uv2;
+}
+
+fn vertex_color(triangle: u32, barycentric: vec2f) -> vec3f {
+    let idx = blas_triangles[triangle].indices;
+    return (1.0 - barycentric.x - barycentric.y) * vertices[idx.x].color
+        + barycentric.x * vertices[idx.y].color + barycentric.y * vertices[idx.z].color;
+}
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    return select(pow((c + 0.055) / 1.055, vec3f(2.4)), c / 12.92, c <= vec3f(0.04045));
+}
+
+// a texel of a texture, repeating it outside of 0 to 1 like most obj files expect
+fn texel(rect: TextureRect, p: vec2i) -> vec4f {
+    let size = vec2i(rect.size);
+    return textureLoad(textures, vec2i(rect.origin) + (p % size + size) % size, rect.layer, 0);
+}
+
+/*
+ * bilinear filtering by hand, a sampler would repeat the whole layer
+ * and blend in the textures packed next to this one
+ */
+fn sample_texture(texture: u32, uv: vec2f) -> vec4f {
+    let rect = texture_rects[texture];
+    let p = uv * vec2f(rect.size) - 0.5;
+    let i = vec2i(floor(p));
+    let f = fract(p);
+    let top = mix(texel(rect, i), texel(rect, i + vec2i(1, 0)), f.x);
+    let bottom = mix(texel(rect, i + vec2i(0, 1)), texel(rect, i + vec2i(1, 1)), f.x);
+    return mix(top, bottom, f.y);
+}
+
+// color maps are stored in srgb like the images they come from
+fn texture_color(texture: u32, uv: vec2f) -> vec3f {
+    return srgb_to_linear(sample_texture(texture, uv).rgb);
+}
+
+fn texture_height(texture: u32, uv: vec2f) -> f32 {
+    return sample_texture(texture, uv).r;
+}
+
+// the material of the hit with its color maps applied
+fn surface_colors(col: Collision) -> Colors {
+    var mat = colors[col.color_idx];
+    mat.diffuse_color *= vertex_color(col.triangle, col.barycentric);
+    let uv = texcoord(col.triangle, col.barycentric);
+    if (mat.diffuse_texture != NO_TEXTURE) {
+        mat.diffuse_color *= texture_color(mat.diffuse_texture, uv);
+    }
+    if (mat.ambient_texture != NO_TEXTURE) {
+        mat.ambient_color *= texture_color(mat.ambient_texture, uv);
+    }
+    if (mat.specular_texture != NO_TEXTURE) {
+        mat.specular_color *= texture_color(mat.specular_texture, uv);
+    }
+    return mat;
+}
+
+/*
+ * the vertex normals interpolated over the triangle, in world space
+ * turned to the side of the geometric normal, which is the one that says where the surface is
+ * triangles without vertex normals are flat
+ */
+fn shading_normal(col: Collision) -> vec3f {
+    let idx = blas_triangles[col.triangle].indices;
+    let n0 = vertices[idx.x].normal;
+    let n1 = vertices[idx.y].normal;
+    let n2 = vertices[idx.z].normal;
+    let b = col.barycentric;
+    let n = transform_normal(instances[col.instance], (1.0 - b.x - b.y) * n0 + b.x * n1 + b.y * n2);
+    if (all(n0 == vec3f(0.0)) || all(n1 == vec3f(0.0)) || all(n2 == vec3f(0.0)) || dot(n, n) <= 0.0) {
+        return col.normal;
+    }
+    return select(normalize(n), -normalize(n), dot(n, col.normal) < 0.0);
+}
+
+/*
+ * the normal of a surface pushed out along its normal by the height map, to first order
+ * with p' = p + h n the tangents become dp/du + dh/du n and dp/dv + dh/dv n,
+ * their cross product is the new normal
+ * the tangents come from how the texture coordinates change over the triangle
+ */
+fn bump_normal(col: Collision, mat: Colors, n: vec3f) -> vec3f {
+    if (mat.bump_texture == NO_TEXTURE) {
+        return n;
+    }
+    let inst = instances[col.instance];
+    let idx = blas_triangles[col.triangle].indices;
+    let e1 = transform_dir(inst.object_to_world, vertices[idx.y].pos - vertices[idx.x].pos);
+    let e2 = transform_dir(inst.object_to_world, vertices[idx.z].pos - vertices[idx.x].pos);
+    let uv0 = vec2f(vertices[idx.x].u, vertices[idx.x].v);
+    let duv1 = vec2f(vertices[idx.y].u, vertices[idx.y].v) - uv0;
+    let duv2 = vec2f(vertices[idx.z].u, vertices[idx.z].v) - uv0;
+    let det = duv1.x * duv2.y - duv1.y * duv2.x;
+    if (abs(det) < 1e-12) {
+        return n;
+    }
+    let dpdu = (duv2.y * e1 - duv1.y * e2) / det;
+    let dpdv = (duv1.x * e2 - duv2.x * e1) / det;
+
+    // finite differences one texel apart
+    let size = vec2f(texture_rects[mat.bump_texture].size);
+    let uv = texcoord(col.triangle, col.barycentric);
+    let h = texture_height(mat.bump_texture, uv);
+    let dhdu = (texture_height(mat.bump_texture, uv + vec2f(1.0 / size.x, 0.0)) - h) * size.x;
+    let dhdv = (texture_height(mat.bump_texture, uv + vec2f(0.0, 1.0 / size.y)) - h) * size.y;
+
+    let g = cross(dpdu, dpdv);
+    let bumped = g + mat.bump_scale * (dhdu * cross(n, dpdv) + dhdv * cross(dpdu, n));
+    // keep it on the side of the geometric normal
+    let oriented = select(bumped, -bumped, dot(g, n) < 0.0);
+    if (dot(oriented, oriented) <= 0.0) {
+        return n;
+    }
+    return normalize(oriented);
+}
+
 // https://iquilezles.org/articles/simplepathtracing/
 fn trace_path(ro0: vec3f, rd0: vec3f) -> vec4f {
     var color = vec3f(0.0);
-    var surface_color = vec3f(1.0);
+    var throughput = vec3f(1.0);
     var ro = ro0;
     var rd = rd0;
-    for (var i = 0; i < 4; i++) {
+    // after a diffuse or glossy bounce emitters could also have been found by light sampling,
+    // after glass or from the camera they could not, so they count fully
+    var delta_bounce = true;
+    var bsdf_pdf_prev = 0.0;
+    // the shading point the last bounce left from, the light tree picks lights for it
+    var pos_prev = ro0;
+    var nor_prev = vec3f(0.0);
+    for (var i = 0u; i < pt_info.max_bounces; i++) {
+        bounce_dimension = CAMERA_DIMENSIONS + i * DIMENSIONS_PER_BOUNCE;
+        /*
+         * russian roulette, a path that can't add much anymore goes on with the chance
+         * of its brightest channel and counts that much more when it does,
+         * so dark paths end early without making the image darker
+         */
+        if (i >= pt_info.roulette_depth) {
+            let survive = min(max(throughput.x, max(throughput.y, throughput.z)), 1.0);
+            if (bounce_sample(DIM_ROULETTE) >= survive) {
+                break;
+            }
+            throughput /= survive;
+        }
         let col = closest_intersection(ro, rd);
 
         if (col.distance < 0.0) {
-            if (i == 0) {
-                color = vec3f(0.0, 0.1, 0.5); //bg/sky color
+            if (sky.enabled != 0u) {
+                // the sky is smooth enough for bsdf sampling alone,
+                // the sun is a punctual light so after other bounces light sampling found it
+                color += throughput * sky_radiance(rd, delta_bounce);
+            } else if (compute_info.env_width == 0u) {
+                // the sky does not light the scene, it is only seen directly or through glass
+                if (delta_bounce) {
+                    color += throughput * SKY_COLOR;
+                }
+            } else if (delta_bounce) {
+                color += throughput * environment_radiance(rd);
+            } else {
+                let weight = power_heuristic(bsdf_pdf_prev, light_strategies().x * environment_pdf(rd));
+                color += throughput * environment_radiance(rd) * weight;
+            }
+            break;
+        }
+
+        let mat = surface_colors(col);
+        let wo = -normalize(rd);
+        // shade the side we hit, the geometric normal decides which one that is
+        let geo = select(col.normal, -col.normal, dot(col.normal, wo) < 0.0);
+        let shading = bump_normal(col, mat, shading_normal(col));
+        let nor = select(shading, -shading, dot(col.normal, wo) < 0.0);
+
+        if (delta_bounce) {
+            color += throughput * mat.ambient_color;
+        } else if (col.light != NO_LIGHT) {
+            let slot = light_slots[col.light];
+            let cos_l = abs(dot(col.normal, wo));
+            let pmf = light_strategies().y * light_pmf(slot, pos_prev, nor_prev);
+            let weight = power_heuristic(bsdf_pdf_prev, light_pdf(lights[slot], pmf, col.distance, cos_l));
+            color += throughput * mat.ambient_color * weight;
+        }
+
+        // glass is a delta bsdf, light sampling can never hit its direction so skip it
+        if (mat.transmission > 0.0 && bounce_sample(DIM_TRANSMISSION) < mat.transmission) {
+            let entering = dot(col.normal, wo) > 0.0;
+            let wi = dielectric_bounce(mat, nor, wo, entering);
+            if (dot(wi, nor) < 0.0) {
+                throughput *= mat.transmission_color;
             }
+            ro = col.position;
+            rd = wi;
+            delta_bounce = true;
+            continue;
+        }
+
+        color += throughput * apply_lighting(col.position, nor, geo, wo, mat);
+        delta_bounce = false;
+
+        let wi = random_bounce(mat, nor, wo);
+        let pdf = bsdf_pdf(mat, nor, wo, wi);
+        // the shading normal can send rays into the surface, they would leak through it
+        if (pdf <= 0.0 || dot(wi, geo) <= 0.0) {
             break;
         }
-        
-        let light = apply_lighting(col.position, col.normal);
-        surface_color *= colors[col.color_idx].diffuse_color;
-        color += surface_color * light; //does this make color end as more than 1?
-        color += colors[col.color_idx].ambient_color;
+        throughput *= eval_bsdf(mat, nor, wo, wi) / pdf;
+        bsdf_pdf_prev = pdf;
+        pos_prev = col.position;
+        nor_prev = nor;
         ro = col.position;
-        rd = random_bounce(col.normal);
+        rd = wi;
     }
 
     return vec4f(color, 1.0);
 }
 
+/*
+ * the bsdf is a lambertian diffuse lobe plus a ggx microfacet specular lobe
+ * the diffuse lobe reflects diffuse_color, the specular lobe uses specular_color
+ * as the reflectance at normal incidence and the schlick approximation for fresnel
+ * the diffuse lobe only gets the light fresnel lets through, so the two never add up to more than came in
+ * all directions are normalized and point away from the surface
+ */
+
+fn luminance(c: vec3f) -> f32 {
+    return dot(c, vec3f(0.2126, 0.7152, 0.0722));
+}
+
+// probability of sampling the specular lobe instead of the diffuse one
+fn specular_probability(mat: Colors) -> f32 {
+    let s = luminance(mat.specular_color);
+    let d = luminance(mat.diffuse_color);
+    if (s + d <= 0.0) {
+        return 0.0;
+    }
+    return s / (s + d);
+}
+
+// https://jcgt.org/published/0006/01/01/
+fn orthonormal_basis(n: vec3f) -> mat3x3f {
+    let sign = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    let t = vec3f(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let bt = vec3f(b, sign + n.y * n.y * a, -n.y);
+    return mat3x3f(t, bt, n);
+}
+
+fn ggx_d(alpha: f32, n_dot_h: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn smith_g1(alpha: f32, n_dot_v: f32) -> f32 {
+    let a2 = alpha * alpha;
+    return 2.0 * n_dot_v / (n_dot_v + sqrt(a2 + (1.0 - a2) * n_dot_v * n_dot_v));
+}
+
+fn fresnel_schlick(f0: vec3f, cos_theta: f32) -> vec3f {
+    return f0 + (vec3f(1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// f * cos(theta_i)
+fn eval_bsdf(mat: Colors, n: vec3f, wo: vec3f, wi: vec3f) -> vec3f {
+    let n_dot_i = dot(n, wi);
+    let n_dot_o = dot(n, wo);
+    if (n_dot_i <= 0.0 || n_dot_o <= 0.0) {
+        return vec3f(0.0);
+    }
+    var f = mat.diffuse_color / PI;
+    if (any(mat.specular_color > vec3f(0.0))) {
+        let h = normalize(wi + wo);
+        let d = ggx_d(mat.roughness, dot(n, h));
+        let g = smith_g1(mat.roughness, n_dot_i) * smith_g1(mat.roughness, n_dot_o);
+        let fr = fresnel_schlick(mat.specular_color, dot(wi, h));
+        // what the specular lobe reflects never gets to the diffuse one below it
+        f = f * (vec3f(1.0) - fr) + fr * d * g / (4.0 * n_dot_i * n_dot_o);
+    }
+    return f * n_dot_i;
+}
+
+// pdf of random_bounce picking wi, with respect to solid angle
+fn bsdf_pdf(mat: Colors, n: vec3f, wo: vec3f, wi: vec3f) -> f32 {
+    let n_dot_i = dot(n, wi);
+    if (n_dot_i <= 0.0) {
+        return 0.0;
+    }
+    let p_spec = specular_probability(mat);
+    var pdf = (1.0 - p_spec) * n_dot_i / PI;
+    if (p_spec > 0.0) {
+        let h = normalize(wi + wo);
+        let n_dot_h = dot(n, h);
+        pdf += p_spec * ggx_d(mat.roughness, n_dot_h) * n_dot_h / (4.0 * abs(dot(wo, h)));
+    }
+    return pdf;
+}
+
+// pick one of the lobes, then a direction from it
+fn random_bounce(mat: Colors, n: vec3f, wo: vec3f) -> vec3f {
+    let p_spec = specular_probability(mat);
+    if (p_spec > 0.0 && bounce_sample(DIM_LOBE) < p_spec) {
+        return ggx_reflect(mat.roughness, n, wo);
+    }
+    return normalize(lambert(n));
+}
+
+// sample a microfacet normal proportional to D(h)cos(theta_h) and mirror wo around it
+fn ggx_reflect(alpha: f32, n: vec3f, wo: vec3f) -> vec3f {
+    let u = bounce_sample_2d(DIM_BOUNCE);
+    let r1 = u.x;
+    let r2 = u.y;
+    let theta = atan(alpha * sqrt(r1 / (1.0 - r1)));
+    let phi = 2.0 * PI * r2;
+    let h = orthonormal_basis(n) * vec3f(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+    return reflect(-wo, h);
+}
+
+/*
+ * smooth glass, either mirror wo or bend it through the surface
+ * we pick one with the fresnel reflectance as probability, so the throughput stays the same
+ * entering tells if we are coming from outside, that needs the normals to point outwards
+ */
+fn dielectric_bounce(mat: Colors, n: vec3f, wo: vec3f, entering: bool) -> vec3f {
+    let eta = select(mat.ior, 1.0 / mat.ior, entering);
+    let cos_o = dot(n, wo);
+    let sin2_t = eta * eta * (1.0 - cos_o * cos_o);
+    // total internal reflection
+    if (sin2_t >= 1.0) {
+        return reflect(-wo, n);
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    if (bounce_sample(DIM_FRESNEL) < fresnel_dielectric(cos_o, cos_t, eta)) {
+        return reflect(-wo, n);
+    }
+    return normalize(-wo * eta + n * (eta * cos_o - cos_t));
+}
 
-fn random_bounce(norm: vec3f) -> vec3f {
-    return lambert(norm);
+// exact fresnel reflectance for unpolarized light, eta is the ratio of the indices
+fn fresnel_dielectric(cos_o: f32, cos_t: f32, eta: f32) -> f32 {
+    let r_s = (eta * cos_o - cos_t) / (eta * cos_o + cos_t);
+    let r_p = (cos_o - eta * cos_t) / (cos_o + eta * cos_t);
+    return 0.5 * (r_s * r_s + r_p * r_p);
 }
 
 // https://web.archive.org/web/20170610002747/http://www.amietia.com/lambertnotangent.html
 fn lambert(norm: vec3f) -> vec3f {
-    let r1 = rand();
-    let r2 = 2.0*rand() - 1.0;
+    let u = bounce_sample_2d(DIM_BOUNCE);
+    let r1 = u.x;
+    let r2 = 2.0*u.y - 1.0;
 
     let theta = 2.0 * PI * r1;
     let sphere_point = vec3f(sqrt(1.0 - r2 * r2) * vec2f(cos(theta), sin(theta)), r2);
@@ -225,17 +1390,77 @@ fn lambert(norm: vec3f) -> vec3f {
 
 @compute @workgroup_size(1)
 fn main(@builtin(global_invocation_id) param: vec3u, @builtin(num_workgroups) num: vec3u) {
-    seed = pt_info.samples_per_pixel*param.x*param.y + param.x + param.y;
-    let ident = mat3x3f(vec3f(1.0, 0.0, 0.0), vec3f(0.0, 1.0, 0.0), vec3f(0.0, 0.0, 1.0));
-    var rd = ident * vec3f(
-        f32(num.x - param.x) - f32(num.x)/2f + rand(),
-        f32(num.y - param.y) - f32(num.y)/2f + rand(),
-        f32(num.y)/2f
+    let i = param.x + param.y*pt_info.width;
+    if (pt_info.error_threshold > 0.0 && convergence.pixels[i].y != 0.0) {
+        return;
+    }
+    /*
+     * every pixel hashes its own seed, the same one for all of its samples so the sobol
+     * scrambling stays put, and the pcg stream of the random sampler
+     * starts from that mixed with the sample, so no two pixels or samples share one
+     * the global seed goes into both, the same seed always renders the same image
+     */
+    pixel = param.xy;
+    pixel_seed = pcg3d(vec3u(param.xy, pt_info.seed)).x;
+    scramble_seed = select(pixel_seed, pcg(pt_info.seed), pt_info.sampler_type == BLUE_NOISE_SAMPLER);
+    // the pixel's own count, with adaptive sampling it falls behind the others when it stops
+    sample_index = u32(pt[i].a);
+    seed = pcg(hash_combine(pixel_seed, sample_index));
+    let jitter = sample_2d(0u);
+    var rd = camera.rot * vec3f(
+        f32(num.x - param.x) - f32(num.x)/2f + jitter.x,
+        f32(num.y - param.y) - f32(num.y)/2f + jitter.y,
+        camera.focal_length
         );
 
     let ro = camera.position;
     var color = trace_path(ro, rd);
-    color = clamp(color, vec4f(0.0), vec4f(1.0)); // i think clamping is a hack
+    // a single nan or inf would stay in the accumulated pixel forever, drop that sample
+    if (any(color != color) || any(abs(color) > vec4f(1e30))) {
+        color = vec4f(0.0, 0.0, 0.0, 1.0);
+    }
     
-    pt[param.x + param.y*pt_info.width] += color;
+    pt[i] += color;
+    let l = luminance(color.rgb);
+    convergence.pixels[i].x += l * l;
+}
+
+// samples every pixel takes before its error estimate is trusted
+const MIN_ADAPTIVE_SAMPLES: f32 = 16.0;
+
+/*
+ * runs after every sample with adaptive sampling, marks the pixels that are done
+ * and counts the rest in convergence.unconverged, when that is 0 the image is finished
+ * a pixel is done when the standard error of its luminance over the square root of it
+ * is below the threshold, for it and its neighbours, so a pixel that happened to miss a small light in its first
+ * samples still gets more when the ones next to it did find it
+ * this is its own pass so no pixel reads a neighbour while that is being sampled
+ */
+@compute @workgroup_size(1)
+fn converge(@builtin(global_invocation_id) param: vec3u) {
+    var error = 0.0;
+    for (var y = max(i32(param.y) - 1, 0); y <= min(i32(param.y) + 1, i32(pt_info.height) - 1); y++) {
+        for (var x = max(i32(param.x) - 1, 0); x <= min(i32(param.x) + 1, i32(pt_info.width) - 1); x++) {
+            error = max(error, pixel_error(u32(x) + u32(y)*pt_info.width));
+        }
+    }
+    let done = error < pt_info.error_threshold;
+    convergence.pixels[param.x + param.y*pt_info.width].y = select(0.0, 1.0, done);
+    if (!done) {
+        atomicAdd(&convergence.unconverged, 1u);
+    }
+}
+
+fn pixel_error(i: u32) -> f32 {
+    let n = pt[i].a;
+    if (n < MIN_ADAPTIVE_SAMPLES) {
+        return 1e30;
+    }
+    let mean = luminance(pt[i].rgb) / n;
+    let variance = max(convergence.pixels[i].x / n - mean * mean, 0.0) * n / (n - 1.0);
+    /*
+     * the display's gamma makes the same error more visible in dark pixels,
+     * but not as much as relative error would say, which never lets dark fireflies finish
+     */
+    return sqrt(variance / n) / sqrt(max(mean, 1e-4));
 }
@@ -2,6 +2,12 @@ struct PtInfo {
     width: u32,
     height: u32,
     samples_per_pixel: u32,
+    max_bounces: u32,
+    seed: u32,
+    sampler_type: u32,
+    restarts: u32,
+    error_threshold: f32,
+    roulette_depth: u32,
 }
 
 @group(0) @binding(0)
@@ -0,0 +1,410 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/*
+ * light coming from infinitely far away in every direction, stored as an equirectangular image
+ * the top row is straight up (+y), the middle row the horizon
+ * rays that leave the scene see it, and it is sampled as a light with importance sampling:
+ * we build a cdf over the rows and one over the pixels of every row, weighted by luminance,
+ * so bright spots like the sun get most of the shadow rays
+ */
+pub struct Environment {
+    width: u32,
+    height: u32,
+    // linear rgb radiance, row by row from the top
+    pixels: Vec<[f32; 3]>,
+    // radians around the up axis
+    pub rotation: f32,
+    // every pixel is multiplied by this
+    pub intensity: f32,
+}
+
+// everything that can go wrong when loading an environment map
+#[derive(Debug)]
+pub enum EnvironmentError {
+    // the file could not be read
+    Io { path: PathBuf, source: io::Error },
+    // the file is not a radiance hdr file we understand
+    Format { path: PathBuf, message: String },
+}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvironmentError::Io { path, source } => {
+                write!(f, "{}: failed to read hdr file: {}", path.display(), source)
+            }
+            EnvironmentError::Format { path, message } => {
+                write!(f, "{}: not a valid hdr file: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvironmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvironmentError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Environment {
+    // panics if there are not width * height pixels
+    pub fn new(width: u32, height: u32, pixels: Vec<[f32; 3]>) -> Environment {
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize,
+            "environment map has the wrong number of pixels"
+        );
+        Environment {
+            width,
+            height,
+            pixels,
+            rotation: 0.0,
+            intensity: 1.0,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /*
+     * what the shader needs to sample the map
+     * the pixels get the pdf of picking them in alpha, with respect to the area of the image
+     * the cdf buffer has the cdf of every row one after the other, then the cdf over the rows
+     * pixels are weighted by the sin of their latitude since rows near the poles cover less of the sphere
+     */
+    pub(crate) fn sampling_data(&self) -> (Vec<[f32; 4]>, Vec<f32>) {
+        let w = self.width as usize;
+        let h = self.height as usize;
+        let weights = self
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let sin_theta = (PI * ((i / w) as f32 + 0.5) / h as f32).sin();
+                crate::light::luminance(*p) * sin_theta
+            })
+            .collect::<Vec<f32>>();
+
+        let mut cdf = Vec::with_capacity(w * h + h);
+        let mut row_sums = Vec::with_capacity(h);
+        for row in weights.chunks(w) {
+            let row_sum = row.iter().sum::<f32>();
+            row_sums.push(row_sum);
+            cdf.extend(running_sum(row, row_sum));
+        }
+        let total = row_sums.iter().sum::<f32>();
+        cdf.extend(running_sum(&row_sums, total));
+
+        let scale = if total > 0.0 {
+            (w * h) as f32 / total
+        } else {
+            0.0
+        };
+        let pixels = self
+            .pixels
+            .iter()
+            .zip(&weights)
+            .map(|(p, weight)| [p[0], p[1], p[2], weight * scale])
+            .collect();
+        (pixels, cdf)
+    }
+}
+
+// cdf of some weights, uniform if they are all 0
+fn running_sum(weights: &[f32], sum: f32) -> Vec<f32> {
+    let n = weights.len();
+    let mut acc = 0.0;
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| {
+            acc += w;
+            if i == n - 1 {
+                1.0
+            } else if sum > 0.0 {
+                acc / sum
+            } else {
+                (i + 1) as f32 / n as f32
+            }
+        })
+        .collect()
+}
+
+/*
+ * read a radiance .hdr (rgbe) file
+ * only the usual -Y height +X width orientation is supported,
+ * scanlines can be flat or run length encoded
+ * http://paulbourke.net/dataformats/pic/
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Environment, EnvironmentError> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path).map_err(|source| EnvironmentError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    read(path, io::BufReader::new(file))
+}
+
+// the path is only for the errors
+fn read<R: BufRead>(path: &Path, mut reader: R) -> Result<Environment, EnvironmentError> {
+    let io_err = |source| EnvironmentError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let format_err = |message: &str| EnvironmentError::Format {
+        path: path.to_path_buf(),
+        message: message.to_string(),
+    };
+
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(io_err)?;
+    if !line.starts_with("#?") {
+        return Err(format_err("missing #? signature"));
+    }
+    // the header ends with an empty line
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(io_err)? == 0 {
+            return Err(format_err("header does not end"));
+        }
+        let l = line.trim();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(format) = l.strip_prefix("FORMAT=")
+            && format != "32-bit_rle_rgbe"
+        {
+            return Err(format_err(&format!("unsupported format {}", format)));
+        }
+    }
+
+    line.clear();
+    reader.read_line(&mut line).map_err(io_err)?;
+    let size = line.split_whitespace().collect::<Vec<&str>>();
+    let (height, width) = match size[..] {
+        ["-Y", h, "+X", w] => (
+            h.parse::<u32>().map_err(|_| format_err("invalid height"))?,
+            w.parse::<u32>().map_err(|_| format_err("invalid width"))?,
+        ),
+        _ => return Err(format_err("unsupported orientation")),
+    };
+    if width == 0 || height == 0 {
+        return Err(format_err("image is empty"));
+    }
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).map_err(io_err)?;
+    let mut data = data.as_slice();
+    let mut pixels = Vec::with_capacity(width as usize * height as usize);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_scanline(&mut data, &mut scanline)
+            .ok_or_else(|| format_err("truncated pixel data"))?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_rgb(rgbe)));
+    }
+    Ok(Environment::new(width, height, pixels))
+}
+
+// None if data runs out
+fn read_scanline(data: &mut &[u8], scanline: &mut [[u8; 4]]) -> Option<()> {
+    let w = scanline.len();
+    let rle = (8..0x8000).contains(&w)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && ((data[2] as usize) << 8 | data[3] as usize) == w;
+    if !rle {
+        for p in scanline.iter_mut() {
+            let (rgbe, rest) = data.split_first_chunk::<4>()?;
+            *p = *rgbe;
+            *data = rest;
+        }
+        return Some(());
+    }
+
+    *data = &data[4..];
+    // every channel is encoded on its own, runs have the high bit set
+    for c in 0..4 {
+        let mut x = 0;
+        while x < w {
+            let (&count, rest) = data.split_first()?;
+            *data = rest;
+            if count > 128 {
+                let n = (count - 128) as usize;
+                let (&value, rest) = data.split_first()?;
+                *data = rest;
+                for p in scanline.get_mut(x..x + n)? {
+                    p[c] = value;
+                }
+                x += n;
+            } else {
+                let n = count as usize;
+                if n == 0 || data.len() < n {
+                    return None;
+                }
+                for (p, &value) in scanline.get_mut(x..x + n)?.iter_mut().zip(&data[..n]) {
+                    p[c] = value;
+                }
+                *data = &data[n..];
+                x += n;
+            }
+        }
+    }
+    Some(())
+}
+
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    [
+        (rgbe[0] as f32 + 0.5) * scale,
+        (rgbe[1] as f32 + 0.5) * scale,
+        (rgbe[2] as f32 + 0.5) * scale,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: usize, height: usize) -> Vec<u8> {
+        format!(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes()
+    }
+
+    // runs of 3 or more become a run, everything else literals
+    fn encode_rle(scanline: &[[u8; 4]]) -> Vec<u8> {
+        let w = scanline.len();
+        let mut out = vec![2, 2, (w >> 8) as u8, w as u8];
+        for c in 0..4 {
+            let values = scanline.iter().map(|p| p[c]).collect::<Vec<u8>>();
+            let mut x = 0;
+            while x < w {
+                let run = values[x..]
+                    .iter()
+                    .take(127)
+                    .take_while(|&&v| v == values[x])
+                    .count();
+                if run >= 3 {
+                    out.extend([128 + run as u8, values[x]]);
+                    x += run;
+                    continue;
+                }
+                let mut end = x + 1;
+                while end < w && end - x < 128 && !values[end..].starts_with(&[values[end]; 3]) {
+                    end += 1;
+                }
+                out.push((end - x) as u8);
+                out.extend(&values[x..end]);
+                x = end;
+            }
+        }
+        out
+    }
+
+    // rgbe pixels with long runs and noise, and some that are black
+    fn image(width: usize, height: usize) -> Vec<[u8; 4]> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                if x < width / 2 {
+                    [200, 100, 50, 128 + y as u8]
+                } else if x % 5 == 0 {
+                    [0; 4]
+                } else {
+                    [
+                        (x * 37 % 256) as u8,
+                        (y * 91 % 256) as u8,
+                        (i * 13 % 256) as u8,
+                        130,
+                    ]
+                }
+            })
+            .collect()
+    }
+
+    fn decoded(rgbe: &[[u8; 4]]) -> Vec<[f32; 3]> {
+        rgbe.iter().map(|&p| rgbe_to_rgb(p)).collect()
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let (width, height) = (40, 3);
+        let rgbe = image(width, height);
+        let mut file = header(width, height);
+        for row in rgbe.chunks(width) {
+            file.extend(encode_rle(row));
+        }
+        let env = read(Path::new("rle.hdr"), file.as_slice()).unwrap();
+        assert_eq!((env.width(), env.height()), (40, 3));
+        assert_eq!(env.pixels, decoded(&rgbe));
+    }
+
+    #[test]
+    fn flat_scanlines() {
+        // too narrow for rle, and wide but stored flat
+        for width in [5, 20] {
+            let rgbe = image(width, 2);
+            let mut file = header(width, 2);
+            file.extend(rgbe.iter().flatten());
+            let env = read(Path::new("flat.hdr"), file.as_slice()).unwrap();
+            assert_eq!(env.pixels, decoded(&rgbe));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let rgbe = image(40, 2);
+        let mut file = header(40, 2);
+        for row in rgbe.chunks(40) {
+            file.extend(encode_rle(row));
+        }
+        file.truncate(file.len() - 10);
+        let result = read(Path::new("truncated.hdr"), file.as_slice());
+        assert!(
+            matches!(&result, Err(EnvironmentError::Format { message, .. }) if message.contains("truncated")),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn cdfs_end_at_1_and_the_pdf_integrates_to_1() {
+        let (width, height) = (16, 8);
+        let mut pixels = decoded(&image(width, height));
+        // a row that is black everywhere gets a uniform cdf
+        pixels[width..2 * width].fill([0.0; 3]);
+        let env = Environment::new(width as u32, height as u32, pixels);
+        let (pixels, cdf) = env.sampling_data();
+        assert_eq!(cdf.len(), width * height + height);
+        for c in cdf
+            .chunks(width)
+            .take(height)
+            .chain([&cdf[width * height..]])
+        {
+            assert!(c.windows(2).all(|w| w[0] <= w[1]), "{:?}", c);
+            assert_eq!(*c.last().unwrap(), 1.0);
+        }
+        assert_eq!(cdf[width], 1.0 / width as f32);
+
+        // alpha is the pdf over the image, so its mean is its integral
+        let integral = pixels.iter().map(|p| p[3]).sum::<f32>() / (width * height) as f32;
+        assert!((integral - 1.0).abs() < 1e-4, "{}", integral);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::model::{Colors, MIN_ROUGHNESS, MeshData, Scene, SceneError, smooth_normals};
+use crate::pt::CameraPose;
+use crate::punctual::PunctualLight;
+use crate::texture::NO_TEXTURE;
+use ::gltf::image::Format;
+use ::gltf::khr_lights_punctual::Kind;
+use glam::{Affine3A, Mat4, Vec3};
+use std::path::Path;
+
+/*
+ * load a .gltf or .glb file
+ * every primitive becomes a mesh since a mesh has a single material,
+ * every node with a mesh places an instance of each of them, with the transforms of its parents
+ * metallic roughness materials are turned into our diffuse plus specular model:
+ * metals reflect their base color, everything else 4% and its base color diffusely
+ * the emissive color (times KHR_materials_emissive_strength) is the radiance it gives off
+ * base color and emissive textures are used, the other textures are left out
+ * vertex colors (COLOR_0) tint the diffuse color
+ * the first camera becomes the camera of the scene
+ * KHR_lights_punctual lights become punctual lights, candela and lux are taken as they are
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let (document, buffers, images) = ::gltf::import(path).map_err(|source| SceneError::Gltf {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let mut scene = Scene::new();
+    // images are only converted once they are used, and only once
+    let mut texture_ids = vec![None; images.len()];
+    let mut texture = |scene: &mut Scene, info: Option<::gltf::texture::Info>| {
+        let Some(info) = info else {
+            return NO_TEXTURE;
+        };
+        let image = info.texture().source().index();
+        *texture_ids[image].get_or_insert_with(|| match to_rgba(&images[image]) {
+            Some(rgba) => scene.add_texture(rgba),
+            None => {
+                log::warn!(
+                    "{}: image {} has an unsupported format {:?}, leaving it out",
+                    path.display(),
+                    image,
+                    images[image].format
+                );
+                NO_TEXTURE
+            }
+        })
+    };
+
+    let mut materials = Vec::new();
+    for m in document.materials() {
+        let pbr = m.pbr_metallic_roughness();
+        let base = pbr.base_color_factor();
+        let base = Vec3::new(base[0], base[1], base[2]);
+        let metallic = pbr.metallic_factor().clamp(0.0, 1.0);
+        let roughness = pbr.roughness_factor().clamp(0.0, 1.0);
+        let emission = Vec3::from(m.emissive_factor()) * m.emissive_strength().unwrap_or(1.0);
+        let diffuse_texture = texture(&mut scene, pbr.base_color_texture());
+        let colors = Colors {
+            ambient_color: emission.to_array(),
+            ior: m.ior().unwrap_or(1.5),
+            diffuse_color: (base * (1.0 - metallic)).to_array(),
+            transmission: m.transmission().map_or(0.0, |t| t.transmission_factor()),
+            specular_color: Vec3::splat(0.04).lerp(base, metallic).to_array(),
+            // gltf roughness is perceptual, the ggx alpha is its square
+            roughness: (roughness * roughness).max(MIN_ROUGHNESS),
+            transmission_color: base.to_array(),
+            diffuse_texture,
+            ambient_texture: texture(&mut scene, m.emissive_texture()),
+            // the base color texture only tints the reflection of pure metals
+            specular_texture: if metallic >= 1.0 {
+                diffuse_texture
+            } else {
+                NO_TEXTURE
+            },
+            ..Default::default()
+        };
+        let name = m
+            .name()
+            .map_or_else(|| format!("material{}", materials.len()), str::to_string);
+        materials.push(scene.add_material(&name, colors));
+    }
+    // primitives without a material get a plain grey one
+    let mut default_material = None;
+
+    // our meshes for the primitives of every gltf mesh
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        let mut ids = Vec::new();
+        for (i, primitive) in mesh.primitives().enumerate() {
+            let name = format!("{}.{}", mesh.name().unwrap_or("mesh"), i);
+            if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+                log::warn!(
+                    "{}: primitive '{}' is not made of triangles, leaving it out",
+                    path.display(),
+                    name
+                );
+                continue;
+            }
+            let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+            let positions = positions.collect::<Vec<[f32; 3]>>();
+            let mut data = MeshData {
+                indices: match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                },
+                normals: reader.read_normals().map_or_else(Vec::new, |n| n.collect()),
+                texcoords: reader
+                    .read_tex_coords(0)
+                    .map_or_else(Vec::new, |t| t.into_f32().collect()),
+                // already linear, they multiply the base color
+                colors: reader
+                    .read_colors(0)
+                    .map_or_else(Vec::new, |c| c.into_rgb_f32().collect()),
+                positions,
+            };
+            if !data.indices.len().is_multiple_of(3) {
+                log::warn!(
+                    "{}: primitive '{}' has {} indices, leaving out the last partial triangle",
+                    path.display(),
+                    name,
+                    data.indices.len()
+                );
+                data.indices.truncate(data.indices.len() / 3 * 3);
+            }
+            if let Some(&i) = data
+                .indices
+                .iter()
+                .find(|&&i| i as usize >= data.positions.len())
+            {
+                return Err(SceneError::Malformed {
+                    path: path.to_path_buf(),
+                    message: format!(
+                        "primitive '{}' uses vertex {} but there are only {}",
+                        name,
+                        i,
+                        data.positions.len()
+                    ),
+                });
+            }
+            if data.indices.len() < 3 {
+                continue;
+            }
+            if data.normals.is_empty() {
+                data = smooth_normals(&data);
+            }
+            let material = match primitive.material().index() {
+                Some(m) => materials[m],
+                None => *default_material
+                    .get_or_insert_with(|| scene.add_material("default", Colors::default())),
+            };
+            let id = scene.add_mesh(&name, &data, material).ok_or_else(|| {
+                SceneError::IndexOverflow {
+                    path: path.to_path_buf(),
+                    object: name,
+                }
+            })?;
+            ids.push(id);
+        }
+        meshes.push(ids);
+    }
+
+    if let Some(root) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        for node in root.nodes() {
+            add_node(&mut scene, &meshes, &node, Mat4::IDENTITY);
+        }
+    }
+
+    if scene.num_instances() == 0 {
+        return Err(SceneError::EmptyScene {
+            path: path.to_path_buf(),
+        });
+    }
+
+    log::info!(
+        "{}: loaded {} meshes with {} triangles in {} instances",
+        path.display(),
+        scene.num_meshes(),
+        scene.num_triangles(),
+        scene.num_instances()
+    );
+
+    Ok(scene)
+}
+
+// a node and everything below it, parent is its parents' transforms together
+fn add_node(scene: &mut Scene, meshes: &[Vec<u32>], node: &::gltf::Node, parent: Mat4) {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+    let position = transform.transform_point3(Vec3::ZERO);
+    // cameras and lights look down their -z
+    let forward = transform
+        .transform_vector3(Vec3::NEG_Z)
+        .normalize_or(Vec3::NEG_Z);
+
+    if let Some(mesh) = node.mesh() {
+        // a zero scale can't be inverted and would upload a nan instance
+        let affine = Affine3A::from_mat4(transform);
+        let determinant = affine.matrix3.determinant();
+        if determinant == 0.0 || !determinant.is_finite() {
+            log::warn!(
+                "skipping node '{}', its transform can't be inverted",
+                node.name().unwrap_or("")
+            );
+        } else {
+            for &id in &meshes[mesh.index()] {
+                scene.add_instance(id, affine, None);
+            }
+        }
+    }
+    if let Some(camera) = node.camera()
+        && let ::gltf::camera::Projection::Perspective(perspective) = camera.projection()
+        && scene.camera().is_none()
+    {
+        // our camera can only turn and tilt, the roll is lost
+        let pose = CameraPose {
+            position,
+            yaw: forward.x.atan2(forward.z),
+            pitch: (-forward.y).clamp(-1.0, 1.0).asin(),
+        };
+        scene.set_camera(pose, perspective.yfov());
+    }
+    if let Some(light) = node.light() {
+        let intensity = Vec3::from(light.color()) * light.intensity();
+        scene.add_punctual_light(match light.kind() {
+            Kind::Point => PunctualLight::Point {
+                position,
+                intensity,
+            },
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => PunctualLight::Spot {
+                position,
+                direction: forward,
+                intensity,
+                cone_angle: outer_cone_angle,
+                falloff_start: inner_cone_angle,
+            },
+            Kind::Directional => PunctualLight::Directional {
+                direction: forward,
+                irradiance: intensity,
+                angular_radius: 0.0,
+            },
+        });
+    }
+
+    for child in node.children() {
+        add_node(scene, meshes, &child, transform);
+    }
+}
+
+// 8 and 16 bit images, float ones are hdr and not what materials use
+fn to_rgba(data: &::gltf::image::Data) -> Option<image::RgbaImage> {
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        _ => return None,
+    };
+    // the high byte of 16 bit channels, they are little endian
+    let channel = |pixel: &[u8], c: usize| pixel[c * bytes + bytes - 1];
+    let pixels = data
+        .pixels
+        .chunks_exact(channels * bytes)
+        .flat_map(|p| match channels {
+            1 => [channel(p, 0), channel(p, 0), channel(p, 0), 255],
+            2 => [channel(p, 0), channel(p, 0), channel(p, 0), channel(p, 1)],
+            3 => [channel(p, 0), channel(p, 1), channel(p, 2), 255],
+            _ => [channel(p, 0), channel(p, 1), channel(p, 2), channel(p, 3)],
+        })
+        .collect();
+    image::RgbaImage::from_raw(data.width, data.height, pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * a .glb with one triangle's worth of positions and the given indices,
+     * placed by each of the given nodes and written to the temp directory
+     * since load takes a path
+     */
+    fn load_nodes(name: &str, indices: &[u32], nodes: &[&str]) -> Result<Scene, SceneError> {
+        let mut bin = Vec::new();
+        for p in [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]] {
+            bin.extend(p.iter().flat_map(|c| c.to_le_bytes()));
+        }
+        bin.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+        let mut json = format!(
+            r#"{{"asset":{{"version":"2.0"}},"buffers":[{{"byteLength":{}}}],
+            "bufferViews":[{{"buffer":0,"byteLength":36}},{{"buffer":0,"byteOffset":36,"byteLength":{}}}],
+            "accessors":[{{"bufferView":0,"componentType":5126,"count":3,"type":"VEC3","min":[0,0,0],"max":[1,1,0]}},
+            {{"bufferView":1,"componentType":5125,"count":{},"type":"SCALAR"}}],
+            "meshes":[{{"primitives":[{{"attributes":{{"POSITION":0}},"indices":1}}]}}],
+            "nodes":[{}],"scenes":[{{"nodes":{:?}}}],"scene":0}}"#,
+            bin.len(),
+            4 * indices.len(),
+            indices.len(),
+            nodes.join(","),
+            (0..nodes.len()).collect::<Vec<_>>()
+        )
+        .into_bytes();
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+
+        let mut glb = b"glTF".to_vec();
+        glb.extend(2u32.to_le_bytes());
+        glb.extend((12 + 8 + json.len() as u32 + 8 + bin.len() as u32).to_le_bytes());
+        glb.extend((json.len() as u32).to_le_bytes());
+        glb.extend(b"JSON");
+        glb.extend(json);
+        glb.extend((bin.len() as u32).to_le_bytes());
+        glb.extend(b"BIN\0");
+        glb.extend(bin);
+
+        let path =
+            std::env::temp_dir().join(format!("renderer-{}-{}.glb", std::process::id(), name));
+        std::fs::write(&path, glb).unwrap();
+        let scene = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        scene
+    }
+
+    fn load_triangle(name: &str, indices: &[u32]) -> Result<Scene, SceneError> {
+        load_nodes(name, indices, &[r#"{"mesh":0}"#])
+    }
+
+    #[test]
+    fn one_triangle() {
+        assert_eq!(load_triangle("one", &[0, 1, 2]).unwrap().num_triangles(), 1);
+    }
+
+    #[test]
+    fn partial_triangle_is_left_out() {
+        assert_eq!(
+            load_triangle("partial", &[0, 1, 2, 2, 1])
+                .unwrap()
+                .num_triangles(),
+            1
+        );
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        let Err(SceneError::Malformed { message, .. }) = load_triangle("range", &[0, 1, 3]) else {
+            panic!("an index past the vertices should not load");
+        };
+        assert!(message.contains("vertex 3"), "{}", message);
+    }
+
+    #[test]
+    fn zero_scale_node_is_skipped() {
+        let scene = load_nodes(
+            "zero",
+            &[0, 1, 2],
+            &[r#"{"mesh":0}"#, r#"{"mesh":0,"scale":[0,0,0]}"#],
+        )
+        .unwrap();
+        assert_eq!(scene.num_instances(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::CONVERGENCE_CHECK;
+use renderer::{Config, Renderer, Scene};
+use std::path::Path;
+use std::time::Instant;
+
+/*
+ * render without a window, for machines that have no display
+ * we accumulate samples per pixel up to the target (or until they converge) and then
+ * read the pt buffer back to write it out as a png
+ */
+pub async fn run<P: AsRef<Path>>(
+    scene: &Scene,
+    width: u32,
+    height: u32,
+    samples: u32,
+    config: &Config,
+    output: P,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let instance = wgpu::Instance::new(&Default::default());
+
+    // No surface to be compatible with. If there is no real GPU we fall back to
+    // a software adapter such as llvmpipe/lavapipe.
+    let adapter = match instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })
+        .await
+    {
+        Ok(adapter) => adapter,
+        Err(_) => {
+            instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    compatible_surface: None,
+                    force_fallback_adapter: true,
+                })
+                .await?
+        }
+    };
+    log::info!("using adapter {:?}", adapter.get_info());
+
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            required_limits: Renderer::required_limits(&adapter)?,
+            ..Default::default()
+        })
+        .await?;
+
+    if let Err(e) = Renderer::check_size(&device, width, height) {
+        crate::size_error(e);
+    }
+    let mut renderer = Renderer::new(&device, &queue, scene, width, height, config)?;
+
+    /*
+     * with adaptive sampling we look whether everything converged every few samples,
+     * asking waits for the gpu so doing it after every sample would stall it
+     */
+    let start = Instant::now();
+    let mut converged = false;
+    while renderer.samples_per_pixel() < samples && !converged {
+        renderer.accumulate((samples - renderer.samples_per_pixel()).min(CONVERGENCE_CHECK));
+        converged = renderer.converged();
+    }
+    let pixels = renderer.read_back();
+    println!(
+        "rendered {} samples per pixel in {} ms{}",
+        renderer.samples_per_pixel(),
+        start.elapsed().as_millis(),
+        if converged {
+            ", every pixel converged"
+        } else {
+            ""
+        }
+    );
+
+    let data = pixels
+        .iter()
+        .flat_map(|p| [to_srgb8(p[0]), to_srgb8(p[1]), to_srgb8(p[2]), 255])
+        .collect::<Vec<u8>>();
+
+    let file = std::fs::File::create(output.as_ref())?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    println!("wrote {}", output.as_ref().display());
+    Ok(())
+}
+
+// the window surface is an srgb format, so do the same encoding it would do for us
+fn to_srgb8(linear: f32) -> u8 {
+    let c = linear.clamp(0.0, 1.0);
+    let s = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (s * 255.0).round() as u8
+}
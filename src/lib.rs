@@ -0,0 +1,28 @@
+/*
+ * path tracer that runs in a wgpu compute shader
+ * load a Scene with model::load (or the gltf, ply and stl loaders) and hand it to a Renderer
+ * together with the device and queue you want to render on
+ */
+mod blue_noise;
+mod bvh;
+pub mod environment;
+pub mod gltf;
+mod light;
+pub mod model;
+pub mod ply;
+mod pt;
+pub mod punctual;
+mod renderer;
+pub mod sky;
+mod sobol;
+pub mod stl;
+mod texture;
+
+// instance transforms are glam types
+pub use environment::{Environment, EnvironmentError};
+pub use glam;
+pub use model::{Scene, SceneError};
+pub use pt::{CameraPose, Config, Sampler};
+pub use punctual::{PunctualLight, PunctualLightError};
+pub use renderer::Renderer;
+pub use sky::Sky;
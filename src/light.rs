@@ -0,0 +1,273 @@
+use crate::bvh::{self, Aabb};
+use glam::{Quat, Vec3};
+
+/*
+ * an emissive triangle in world space, in the order the leaves of the light tree refer to them
+ * the shader walks the tree down to pick a light for a shading point,
+ * and up from leaf to the root to find out how likely it was to pick a light it hit by chance
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Light {
+    v0: [f32; 3],
+    // the leaf this light is in
+    leaf: u32,
+    v1: [f32; 3],
+    power: f32,
+    v2: [f32; 3],
+    material: u32,
+    // index into the blas triangles, for the texture coordinates
+    triangle: u32,
+    _padding: [u32; 3],
+}
+
+/*
+ * a node of the light tree, with the same layout as bvh nodes for the first half
+ * the cone bounds the normals of every light below it, emitters are two sided
+ * so a normal and its opposite count as the same direction
+ * together with the bounding box and the power this gives an upper bound
+ * of how much light the node can send to a point, which is what the traversal picks by
+ * https://pbr-book.org/4ed/Light_Sources/Light_Sampling#BVHLightSampling
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightNode {
+    aabb_min: [f32; 3],
+    left_or_first: u32,
+    aabb_max: [f32; 3],
+    count: u32,
+    axis: [f32; 3],
+    // cos of the angle between the axis and the normal furthest away from it
+    cos_theta_o: f32,
+    power: f32,
+    // the root is its own parent
+    parent: u32,
+    _padding: [u32; 2],
+}
+
+pub struct LightTriangle {
+    pub vertices: [Vec3; 3],
+    // the blas triangle it came from
+    pub triangle: u32,
+    pub material: u32,
+    // area times emitted luminance
+    pub power: f32,
+}
+
+pub struct LightTree {
+    pub nodes: Vec<LightNode>,
+    pub lights: Vec<Light>,
+    // where every triangle passed to build ended up in lights, NO_SLOT if it was left out
+    pub slots: Vec<u32>,
+}
+
+pub const NO_SLOT: u32 = u32::MAX;
+
+pub fn luminance(c: [f32; 3]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Cone {
+    axis: Vec3,
+    cos_theta: f32,
+}
+
+impl Cone {
+    // the smallest cone that holds both, see DirectionCone::Union in pbrt
+    fn union(self, other: Cone) -> Cone {
+        // two sided, so turn other around if that brings it closer
+        let b_axis = if self.axis.dot(other.axis) < 0.0 {
+            -other.axis
+        } else {
+            other.axis
+        };
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = self.axis.angle_between(b_axis);
+        if (theta_d + theta_b).min(std::f32::consts::PI) <= theta_a {
+            return self;
+        }
+        if (theta_d + theta_a).min(std::f32::consts::PI) <= theta_b {
+            return Cone {
+                axis: b_axis,
+                cos_theta: other.cos_theta,
+            };
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        let wr = self.axis.cross(b_axis);
+        if theta_o >= std::f32::consts::PI || wr.length_squared() == 0.0 {
+            return Cone {
+                axis: self.axis,
+                cos_theta: -1.0,
+            };
+        }
+        Cone {
+            axis: Quat::from_axis_angle(wr.normalize(), theta_o - theta_a) * self.axis,
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+// the cones of some lights, None if none of them has a direction
+fn union(cones: impl Iterator<Item = Option<Cone>>) -> Option<Cone> {
+    cones.flatten().reduce(Cone::union)
+}
+
+/*
+ * triangles without power are left out, pick_light could never choose them
+ * and a node without power would have nothing to divide by, their slot is NO_SLOT
+ */
+pub fn build(triangles: &[LightTriangle]) -> LightTree {
+    let emitting = (0..triangles.len())
+        .filter(|&i| triangles[i].power > 0.0 && triangles[i].power.is_finite())
+        .collect::<Vec<usize>>();
+    let mut slots = vec![NO_SLOT; triangles.len()];
+    if emitting.is_empty() {
+        return LightTree {
+            nodes: Vec::new(),
+            lights: Vec::new(),
+            slots,
+        };
+    }
+    let triangles = emitting
+        .iter()
+        .map(|&i| &triangles[i])
+        .collect::<Vec<&LightTriangle>>();
+    let bounds = triangles
+        .iter()
+        .map(|t| Aabb::from_points(t.vertices))
+        .collect::<Vec<Aabb>>();
+    let cones = triangles
+        .iter()
+        .map(|t| {
+            let normal = (t.vertices[1] - t.vertices[0]).cross(t.vertices[2] - t.vertices[0]);
+            (normal.length_squared() > 0.0).then(|| Cone {
+                axis: normal.normalize(),
+                cos_theta: 1.0,
+            })
+        })
+        .collect::<Vec<Option<Cone>>>();
+    let tree = bvh::build(&bounds);
+
+    let mut nodes = tree
+        .nodes
+        .iter()
+        .map(|n| LightNode {
+            aabb_min: n.aabb_min,
+            left_or_first: n.left_or_first,
+            aabb_max: n.aabb_max,
+            count: n.count,
+            axis: [0.0; 3],
+            cos_theta_o: 1.0,
+            power: 0.0,
+            parent: 0,
+            _padding: [0; 2],
+        })
+        .collect::<Vec<LightNode>>();
+    let mut lights = vec![bytemuck::Zeroable::zeroed(); triangles.len()];
+    for (slot, &t) in tree.order.iter().enumerate() {
+        let tri = triangles[t as usize];
+        slots[emitting[t as usize]] = slot as u32;
+        lights[slot] = Light {
+            v0: tri.vertices[0].to_array(),
+            leaf: 0,
+            v1: tri.vertices[1].to_array(),
+            power: tri.power,
+            v2: tri.vertices[2].to_array(),
+            material: tri.material,
+            triangle: tri.triangle,
+            _padding: [0; 3],
+        };
+    }
+    let ordered_cones = tree
+        .order
+        .iter()
+        .map(|&t| cones[t as usize])
+        .collect::<Vec<Option<Cone>>>();
+    fit(&mut nodes, &mut lights, &ordered_cones, 0);
+
+    LightTree {
+        nodes,
+        lights,
+        slots,
+    }
+}
+
+// fill in the cone, power and parent links of a node and everything below it
+fn fit(
+    nodes: &mut [LightNode],
+    lights: &mut [Light],
+    cones: &[Option<Cone>],
+    idx: usize,
+) -> Option<Cone> {
+    let node = nodes[idx];
+    let first = node.left_or_first as usize;
+    let (cone, power) = if node.count > 0 {
+        let range = first..first + node.count as usize;
+        for light in &mut lights[range.clone()] {
+            light.leaf = idx as u32;
+        }
+        (
+            union(cones[range.clone()].iter().copied()),
+            lights[range].iter().map(|l| l.power).sum(),
+        )
+    } else {
+        let left = fit(nodes, lights, cones, first);
+        let right = fit(nodes, lights, cones, first + 1);
+        nodes[first].parent = idx as u32;
+        nodes[first + 1].parent = idx as u32;
+        (
+            union([left, right].into_iter()),
+            nodes[first].power + nodes[first + 1].power,
+        )
+    };
+    if let Some(cone) = cone {
+        nodes[idx].axis = cone.axis.to_array();
+        nodes[idx].cos_theta_o = cone.cos_theta;
+    }
+    nodes[idx].power = power;
+    cone
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle(x: f32, power: f32) -> LightTriangle {
+        LightTriangle {
+            vertices: [
+                Vec3::new(x, 0.0, 0.0),
+                Vec3::new(x + 1.0, 0.0, 0.0),
+                Vec3::new(x, 1.0, 0.0),
+            ],
+            triangle: 0,
+            material: 0,
+            power,
+        }
+    }
+
+    #[test]
+    fn triangles_without_power_are_left_out() {
+        let tree = build(&[
+            triangle(0.0, 1.0),
+            triangle(2.0, 0.0),
+            triangle(4.0, 3.0),
+            triangle(6.0, f32::NAN),
+        ]);
+        assert_eq!(tree.lights.len(), 2);
+        assert_eq!(tree.slots[1], NO_SLOT);
+        assert_eq!(tree.slots[3], NO_SLOT);
+        assert_eq!(tree.lights[tree.slots[0] as usize].power, 1.0);
+        assert_eq!(tree.lights[tree.slots[2] as usize].power, 3.0);
+        assert_eq!(tree.nodes[0].power, 4.0);
+    }
+
+    #[test]
+    fn no_power_at_all() {
+        let tree = build(&[triangle(0.0, 0.0), triangle(2.0, 0.0)]);
+        assert!(tree.nodes.is_empty() && tree.lights.is_empty());
+        assert_eq!(tree.slots, [NO_SLOT, NO_SLOT]);
+    }
+}
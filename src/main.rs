@@ -1,6 +1,11 @@
-mod model;
-mod pt;
+mod camera_controller;
+mod headless;
+use camera_controller::CameraController;
+use clap::{CommandFactory, Parser, error::ErrorKind};
 use pollster::FutureExt;
+use renderer::glam::Vec3;
+use renderer::{CameraPose, Config, Renderer, Sampler, Scene, Sky};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use winit::{
@@ -11,17 +16,195 @@ use winit::{
     window::{Window, WindowId},
 };
 
-#[derive(Default)]
+// samples between checks whether adaptive sampling is done
+const CONVERGENCE_CHECK: u32 = 16;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Path traced renderer in WebGPU")]
+struct Args {
+    /// Scene to render (.obj, .gltf, .glb, .ply or .stl)
+    #[arg(default_value = "res/cornell_box.obj")]
+    scene: PathBuf,
+
+    /// Width of the image in pixels
+    #[arg(long, default_value_t = 800, value_parser = clap::value_parser!(u32).range(1..))]
+    width: u32,
+
+    /// Height of the image in pixels
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u32).range(1..))]
+    height: u32,
+
+    /// Samples per pixel to accumulate, 0 keeps going forever in a window
+    #[arg(long, default_value_t = 100)]
+    spp: u32,
+
+    /// Maximum number of path segments traced per sample
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    max_bounces: u32,
+
+    /// Bounces before russian roulette may end dark paths early, --max-bounces or more turns it off
+    #[arg(long, default_value_t = 3)]
+    roulette_depth: u32,
+
+    /// Seed for the random numbers, the same seed renders the same image
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+
+    /// Where the random numbers come from: sobol (low discrepancy), blue-noise (sobol with
+    /// the error spread evenly, best at few samples) or random (white noise)
+    #[arg(long, default_value_t = Sampler::Sobol)]
+    sampler: Sampler,
+
+    /// Adaptive sampling: pixels stop once the error of their brightness over its square root
+    /// is below this (e.g. 0.01), --spp is then the most samples a pixel gets
+    #[arg(long, value_parser = parse_error_threshold)]
+    adaptive: Option<f32>,
+
+    /// Camera position as x,y,z, defaults to the camera of the scene or 250,250,-500
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    camera: Option<[f32; 3]>,
+
+    /// Vertical field of view in degrees, defaults to the one of the scene camera or 90
+    #[arg(long, value_parser = parse_fov)]
+    fov: Option<f32>,
+
+    /// Camera movement speed in scene units per second
+    #[arg(long, default_value_t = 200.0)]
+    move_speed: f32,
+
+    /// Environment map to light the scene with (.hdr)
+    #[arg(long)]
+    env: Option<PathBuf>,
+
+    /// Rotation of the environment map around the up axis in degrees
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    env_rotation: f32,
+
+    /// Multiplier for the radiance of the environment map
+    #[arg(long, default_value_t = 1.0)]
+    env_intensity: f32,
+
+    /// Light the scene with an analytic daylight sky and sun instead of an environment map
+    #[arg(long, conflicts_with = "env")]
+    sky: bool,
+
+    /// Height of the sun above the horizon in degrees
+    #[arg(long, default_value_t = 45.0, allow_hyphen_values = true)]
+    sun_elevation: f32,
+
+    /// Direction of the sun around the up axis in degrees, 0 is towards +z
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    sun_azimuth: f32,
+
+    /// Haziness of the sky, from 2 (clear) to 10 (hazy)
+    #[arg(long, default_value_t = 3.0)]
+    turbidity: f32,
+
+    /// Color of the ground below the horizon as r,g,b
+    #[arg(long, default_value = "0.3,0.3,0.3", value_parser = parse_vec3)]
+    ground_albedo: [f32; 3],
+
+    /// Point, spot and directional lights to add to the scene
+    #[arg(long)]
+    lights: Option<PathBuf>,
+
+    /// Render without a window and write the result to --output
+    #[arg(long)]
+    headless: bool,
+
+    /// Where to write the image in headless mode
+    #[arg(short, long, default_value = "render.png")]
+    output: PathBuf,
+}
+
+impl Args {
+    // --camera puts a camera looking down +z there, otherwise the scene's own camera is used
+    fn config(&self, scene: &Scene) -> Config {
+        let (camera, vertical_fov) = match (self.camera, scene.camera()) {
+            (None, Some(camera)) => camera,
+            (position, _) => (
+                CameraPose {
+                    position: Vec3::from(position.unwrap_or([250.0, 250.0, -500.0])),
+                    yaw: 0.0,
+                    pitch: 0.0,
+                },
+                90f32.to_radians(),
+            ),
+        };
+        Config {
+            camera,
+            vertical_fov: self.fov.map_or(vertical_fov, f32::to_radians),
+            max_bounces: self.max_bounces,
+            roulette_depth: self.roulette_depth,
+            seed: self.seed,
+            sampler: self.sampler,
+            error_threshold: self.adaptive,
+        }
+    }
+}
+
+fn parse_vec3(s: &str) -> Result<[f32; 3], String> {
+    let parts = s
+        .split(',')
+        .map(|x| {
+            x.trim()
+                .parse::<f32>()
+                .map_err(|e| format!("'{}' is not a number: {}", x, e))
+        })
+        .collect::<Result<Vec<f32>, String>>()?;
+    parts
+        .try_into()
+        .map_err(|v: Vec<f32>| format!("expected 3 comma separated numbers, got {}", v.len()))
+}
+
+fn parse_error_threshold(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(threshold) if threshold > 0.0 => Ok(threshold),
+        Ok(_) => Err("must be above 0".to_string()),
+        Err(e) => Err(format!("'{}' is not a number: {}", s, e)),
+    }
+}
+
+// --width and --height are only known to be too big once we have a device
+fn size_error(message: String) -> ! {
+    Args::command()
+        .error(ErrorKind::ValueValidation, message)
+        .exit()
+}
+
+fn parse_fov(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(fov) if fov > 0.0 && fov < 180.0 => Ok(fov),
+        Ok(_) => Err("must be between 0 and 180 degrees".to_string()),
+        Err(e) => Err(format!("'{}' is not a number: {}", s, e)),
+    }
+}
+
 struct App {
+    args: Args,
+    scene: Scene,
     state: Option<State>,
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = event_loop
-            .create_window(Window::default_attributes().with_title("Renderer"))
+            .create_window(
+                Window::default_attributes()
+                    .with_title("Renderer")
+                    .with_inner_size(winit::dpi::PhysicalSize::new(
+                        self.args.width,
+                        self.args.height,
+                    )),
+            )
             .unwrap();
-        self.state = Some(State::new(window).block_on());
+        match State::new(window, &self.scene, &self.args).block_on() {
+            Ok(state) => self.state = Some(state),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                event_loop.exit();
+            }
+        }
     }
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
         match event {
@@ -35,6 +218,30 @@ impl ApplicationHandler for App {
                     },
                 ..
             } => event_loop.exit(),
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(key),
+                        state: key_state,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(state) = self.state.as_mut() {
+                    state.camera_controller.process_keyboard(key, key_state);
+                }
+            }
+            WindowEvent::MouseInput {
+                state: button_state,
+                button,
+                ..
+            } => {
+                if let Some(state) = self.state.as_mut() {
+                    state
+                        .camera_controller
+                        .process_mouse_button(button, button_state);
+                }
+            }
             WindowEvent::Resized(physical_size) => {
                 self.state.as_mut().unwrap().resize(physical_size);
             }
@@ -61,20 +268,106 @@ impl ApplicationHandler for App {
             _ => (),
         }
     }
+    fn device_event(&mut self, _event_loop: &ActiveEventLoop, _id: DeviceId, event: DeviceEvent) {
+        if let (Some(state), DeviceEvent::MouseMotion { delta }) = (self.state.as_mut(), event) {
+            state
+                .camera_controller
+                .process_mouse_motion(delta.0, delta.1);
+        }
+    }
     fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
         // tbh should remove this and decouple background math from refresh rate
-        match self.state.as_ref() {
-            Some(state) => {
-                state.window.request_redraw();
-            }
-            _ => {}
+        if let Some(state) = self.state.as_ref() {
+            state.window.request_redraw();
         }
     }
 }
 async fn run() {
     env_logger::init();
+    let args = Args::parse();
+    if args.headless && args.spp == 0 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--spp 0 keeps going forever, which only works in a window",
+            )
+            .exit();
+    }
+
+    let extension = args
+        .scene
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    let scene = match extension.as_deref() {
+        Some("gltf" | "glb") => renderer::gltf::load(&args.scene),
+        Some("ply") => renderer::ply::load(&args.scene),
+        Some("stl") => renderer::stl::load(&args.scene),
+        _ => renderer::model::load(&args.scene),
+    };
+    let mut scene = match scene {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &args.env {
+        match renderer::environment::load(path) {
+            Ok(mut env) => {
+                env.rotation = args.env_rotation.to_radians();
+                env.intensity = args.env_intensity;
+                scene.set_environment(env);
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.sky {
+        scene.set_sky(Sky {
+            sun_elevation: args.sun_elevation.to_radians(),
+            sun_azimuth: args.sun_azimuth.to_radians(),
+            turbidity: args.turbidity,
+            ground_albedo: args.ground_albedo,
+            ..Default::default()
+        });
+    }
+    if let Some(path) = &args.lights {
+        match renderer::punctual::load(path) {
+            Ok(lights) => lights
+                .into_iter()
+                .for_each(|light| scene.add_punctual_light(light)),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.headless {
+        if let Err(e) = headless::run(
+            &scene,
+            args.width,
+            args.height,
+            args.spp,
+            &args.config(&scene),
+            &args.output,
+        )
+        .await
+        {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
-    let mut app = App::default();
+    let mut app = App {
+        args,
+        scene,
+        state: None,
+    };
 
     _ = event_loop.run_app(&mut app);
 }
@@ -87,12 +380,20 @@ struct State {
     queue: wgpu::Queue,
     surface_config: wgpu::SurfaceConfiguration,
     render_pipeline: wgpu::RenderPipeline,
-    pt: pt::Pt,
+    renderer: Renderer,
+    camera_controller: CameraController,
+    spp_target: u32,
+    // whether adaptive sampling finished, only looked at every CONVERGENCE_CHECK samples
+    converged: bool,
     last_render_time: Instant,
 }
 
 impl State {
-    async fn new(window: Window) -> Self {
+    async fn new(
+        window: Window,
+        scene: &Scene,
+        args: &Args,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let window_arc = Arc::new(window);
         let size = window_arc.inner_size();
 
@@ -102,7 +403,7 @@ impl State {
         let instance = wgpu::Instance::new(&Default::default());
 
         // Draw to this surface, based on a raw window handle
-        let surface = instance.create_surface(window_arc.clone()).unwrap();
+        let surface = instance.create_surface(window_arc.clone())?;
 
         // Handle to a physical graphics and/or compute device.
         // Used for request_device(), then not needed.
@@ -112,11 +413,15 @@ impl State {
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             })
-            .await
-            .unwrap();
+            .await?;
 
         // Actual connection to the GPU
-        let (device, queue) = adapter.request_device(&Default::default()).await.unwrap();
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                required_limits: Renderer::required_limits(&adapter)?,
+                ..Default::default()
+            })
+            .await?;
 
         // This is needed for color format, size, alpha, other stuff
         let surface_config = surface
@@ -126,7 +431,17 @@ impl State {
         surface.configure(&device, &surface_config);
         // We now have a surface we can draw to using our device and queue
 
-        let pt = pt::Pt::new(&device, size);
+        if let Err(e) = Renderer::check_size(&device, size.width, size.height) {
+            size_error(e);
+        }
+        let renderer = Renderer::new(
+            &device,
+            &queue,
+            scene,
+            size.width,
+            size.height,
+            &args.config(scene),
+        )?;
 
         let draw_shader = device.create_shader_module(wgpu::include_wgsl!("draw.wgsl"));
 
@@ -137,7 +452,7 @@ impl State {
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[pt.bind_group_layout()],
+                bind_group_layouts: &[renderer.bind_group_layout()],
                 push_constant_ranges: &[],
             });
 
@@ -175,7 +490,7 @@ impl State {
             cache: None,
         });
 
-        State {
+        Ok(State {
             window: window_arc,
             size,
             surface,
@@ -183,23 +498,31 @@ impl State {
             queue,
             surface_config,
             render_pipeline,
-            pt,
+            renderer,
+            camera_controller: CameraController::new(args.move_speed, 0.004),
+            spp_target: args.spp,
+            converged: false,
             last_render_time: Instant::now(),
-        }
+        })
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        if let Err(e) = Renderer::check_size(&self.device, new_size.width, new_size.height) {
+            log::warn!("not resizing: {}", e);
+            return;
+        }
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.surface_config.width = new_size.width;
             self.surface_config.height = new_size.height;
             self.surface.configure(&self.device, &self.surface_config);
-            self.pt.resize(&self.device, new_size);
+            self.renderer.resize(new_size.width, new_size.height);
         }
     }
 
-    fn update(&mut self, _dt: Duration) {
-        //todo: add movement
+    fn update(&mut self, dt: Duration) {
+        let pose = self.camera_controller.update(self.renderer.camera(), dt);
+        self.renderer.set_camera(pose);
     }
 
     // can make this non-mutating if I build the pt continuously in a separate thread
@@ -210,15 +533,28 @@ impl State {
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
-        self.pt.next_frame(&self.queue);
-
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Command Encoder"),
             });
+
+        /*
+         * asking about convergence waits for the gpu, so like headless it is only done every few samples
+         * a reset from moving or resizing starts the count at 0 again
+         */
+        let spp = self.renderer.samples_per_pixel();
+        if spp == 0 {
+            self.converged = false;
+        } else if spp.is_multiple_of(CONVERGENCE_CHECK) && !self.converged {
+            self.converged = self.renderer.converged();
+        }
+
         // first the compute pass will calculate the path tracing result
-        self.pt.encode_compute(&mut encoder);
+        // once we reach the sample target or every pixel converged we only keep redrawing the result
+        if (self.spp_target == 0 || spp < self.spp_target) && !self.converged {
+            self.renderer.encode_sample(&mut encoder);
+        }
 
         // then the render pass will copy the result onto the screen
         {
@@ -239,7 +575,7 @@ impl State {
             });
 
             render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, self.pt.bind_group(), &[]);
+            render_pass.set_bind_group(0, self.renderer.bind_group(), &[]);
             render_pass.draw(0..3, 0..1); // one triangle that covers whole screen
         }
 
@@ -1,4 +1,14 @@
-use std::path::Path;
+use crate::bvh;
+use crate::environment::Environment;
+use crate::light::{self, Light, LightNode, LightTriangle};
+use crate::pt::CameraPose;
+use crate::punctual::{GpuPunctualLight, PunctualLight};
+use crate::sky::{GpuSky, Sky};
+use crate::texture::{self, NO_TEXTURE};
+use glam::{Affine3A, Vec3};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use wgpu::util::DeviceExt;
 
@@ -6,6 +16,13 @@ use wgpu::util::DeviceExt;
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
     position: [f32; 3],
+    // texture coordinate, with v going down the image
+    u: f32,
+    // zero if the obj file has none
+    normal: [f32; 3],
+    v: f32,
+    // multiplies the diffuse color, white unless the file has vertex colors
+    color: [f32; 3],
     _padding: u32,
 }
 
@@ -14,83 +31,853 @@ struct Vertex {
 struct MeshInfo {
     vertex_offset: u32,
     index_offset: u32,
+    // root of this mesh's tree in the blas node buffer
+    bvh_root: u32,
+    // used unless an instance overrides it
+    material: u32,
 }
 
+// a triangle with absolute vertex indices, in the order the blas leaves refer to them
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Colors {
-    ambient_color: [f32; 3],
+struct BvhTriangle {
+    indices: [u32; 3],
     _padding: u32,
-    diffuse_color: [f32; 3],
-    _padding2: u32,
+}
+
+/*
+* one placement of a mesh in the world
+* the transforms are the top three rows of a 4x4 matrix,
+* the shader multiplies them with a row vector from the left
+*/
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Instance {
+    object_to_world: [[f32; 4]; 3],
+    world_to_object: [[f32; 4]; 3],
+    mesh: u32,
+    // NO_MATERIAL to use the material of the mesh
+    material: u32,
+    // index of the light of the first triangle, NO_LIGHT if the instance does not emit
+    // filled in when the scene is uploaded
+    first_light: u32,
+    _padding: u32,
+}
+
+// the triangles of a mesh as a loader reads them, before they go into the scene buffers
+// normals, texcoords and colors can be empty, otherwise there is one for every position
+#[derive(Debug, Default)]
+pub(crate) struct MeshData {
+    pub(crate) positions: Vec<[f32; 3]>,
+    pub(crate) normals: Vec<[f32; 3]>,
+    pub(crate) texcoords: Vec<[f32; 2]>,
+    // linear rgb
+    pub(crate) colors: Vec<[f32; 3]>,
+    // relative to the first position
+    pub(crate) indices: Vec<u32>,
+}
+
+const NO_MATERIAL: u32 = u32::MAX;
+const NO_LIGHT: u32 = u32::MAX;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Colors {
+    pub(crate) ambient_color: [f32; 3],
+    // index of refraction of the transmissive part
+    pub(crate) ior: f32,
+    pub(crate) diffuse_color: [f32; 3],
+    // 0 is opaque, 1 is clear glass
+    pub(crate) transmission: f32,
+    pub(crate) specular_color: [f32; 3],
+    // ggx alpha of the specular lobe
+    pub(crate) roughness: f32,
+    // tints light that goes through the surface
+    pub(crate) transmission_color: [f32; 3],
+    // indices of the scene's textures, NO_TEXTURE if the material has none
+    // the color maps multiply their color
+    pub(crate) diffuse_texture: u32,
+    pub(crate) ambient_texture: u32,
+    pub(crate) specular_texture: u32,
+    // a height map, bump_scale is how many scene units white is above black
+    pub(crate) bump_texture: u32,
+    pub(crate) bump_scale: f32,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct ComputeInfo {
     num_meshes: u32,
+    // emissive triangles in the light buffer
     num_lights: u32,
+    // 0 if there is no environment map
+    env_width: u32,
+    env_height: u32,
+    env_rotation: f32,
+    env_intensity: f32,
+    num_punctual_lights: u32,
+    _padding: u32,
+}
+
+impl Default for Colors {
+    // grey and diffuse
+    fn default() -> Self {
+        Colors {
+            ambient_color: DEFAULT_AMBIENT,
+            ior: DEFAULT_IOR,
+            diffuse_color: DEFAULT_DIFFUSE,
+            transmission: 0.0,
+            specular_color: DEFAULT_SPECULAR,
+            roughness: 1.0,
+            transmission_color: DEFAULT_TRANSMISSION_COLOR,
+            diffuse_texture: NO_TEXTURE,
+            ambient_texture: NO_TEXTURE,
+            specular_texture: NO_TEXTURE,
+            bump_texture: NO_TEXTURE,
+            bump_scale: 1.0,
+        }
+    }
+}
+
+// used when a material leaves out one of its colors
+const DEFAULT_AMBIENT: [f32; 3] = [0.0, 0.0, 0.0];
+const DEFAULT_DIFFUSE: [f32; 3] = [0.5, 0.5, 0.5];
+const DEFAULT_SPECULAR: [f32; 3] = [0.0, 0.0, 0.0];
+const DEFAULT_IOR: f32 = 1.5;
+const DEFAULT_TRANSMISSION_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+// anything smoother than this is numerically a mirror anyway
+pub(crate) const MIN_ROUGHNESS: f32 = 0.01;
+// faces further apart than this keep a hard edge when we compute vertex normals
+const SMOOTH_ANGLE: f32 = 45.0;
+
+// everything that can go wrong when loading a scene
+#[derive(Debug)]
+pub enum SceneError {
+    // the obj file could not be read or parsed
+    Parse {
+        path: PathBuf,
+        source: tobj::LoadError,
+    },
+    // the mtl file the obj file refers to could not be read or parsed
+    MissingMtl {
+        path: PathBuf,
+        source: tobj::LoadError,
+    },
+    // an object without a material, or with one the mtl file does not define
+    MissingMaterial {
+        path: PathBuf,
+        object: String,
+    },
+    // a material with neither an ambient nor a diffuse color
+    MissingColors {
+        path: PathBuf,
+        material: String,
+    },
+    // indices past the vertices, or more vertices or indices than fit in the u32 offsets of the shader
+    IndexOverflow {
+        path: PathBuf,
+        object: String,
+    },
+    // the gltf file or one of the buffers or images it refers to could not be read
+    Gltf {
+        path: PathBuf,
+        source: ::gltf::Error,
+    },
+    // a ply or stl file could not be read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    // a ply or stl file that is cut short or not what its header says, or a gltf mesh with indices past its vertices
+    Malformed {
+        path: PathBuf,
+        message: String,
+    },
+    // nothing to render
+    EmptyScene {
+        path: PathBuf,
+    },
+    // one of the buffers the scene is uploaded in is bigger than the device allows
+    TooLarge {
+        buffer: String,
+        size: u64,
+        limit: u64,
+    },
+    // more textures than the table the shader finds them in holds
+    TooManyTextures {
+        count: usize,
+        limit: usize,
+    },
+    // the textures do not fit in as many texture array layers as the device allows
+    TooManyTextureLayers {
+        layers: u32,
+        limit: u32,
+    },
+    // the device can't bind as many storage buffers as the scene is spread over
+    TooFewStorageBuffers {
+        needed: u32,
+        limit: u32,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Parse { path, source } => {
+                write!(f, "{}: failed to load obj: {}", path.display(), source)
+            }
+            SceneError::MissingMtl { path, source } => write!(
+                f,
+                "{}: failed to load the mtl file it refers to: {}",
+                path.display(),
+                source
+            ),
+            SceneError::MissingMaterial { path, object } => write!(
+                f,
+                "{}: object '{}' has no material defined in the mtl file",
+                path.display(),
+                object
+            ),
+            SceneError::MissingColors { path, material } => write!(
+                f,
+                "{}: material '{}' has neither Ka nor Kd",
+                path.display(),
+                material
+            ),
+            SceneError::IndexOverflow { path, object } => write!(
+                f,
+                "{}: object '{}' has indices past its vertices, or too many vertices or indices",
+                path.display(),
+                object
+            ),
+            SceneError::Gltf { path, source } => {
+                write!(f, "{}: failed to load gltf: {}", path.display(), source)
+            }
+            SceneError::Io { path, source } => {
+                write!(f, "{}: failed to read: {}", path.display(), source)
+            }
+            SceneError::Malformed { path, message } => write!(f, "{}: {}", path.display(), message),
+            SceneError::EmptyScene { path } => {
+                write!(f, "{}: scene contains no triangles", path.display())
+            }
+            SceneError::TooLarge {
+                buffer,
+                size,
+                limit,
+            } => write!(
+                f,
+                "the scene needs a {} of {} bytes, but the device allows at most {}",
+                buffer, size, limit
+            ),
+            SceneError::TooManyTextures { count, limit } => write!(
+                f,
+                "the scene has {} textures, but at most {} are supported",
+                count, limit
+            ),
+            SceneError::TooManyTextureLayers { layers, limit } => write!(
+                f,
+                "the scene's textures need {} texture array layers, but the device allows at most {}",
+                layers, limit
+            ),
+            SceneError::TooFewStorageBuffers { needed, limit } => write!(
+                f,
+                "the device does not support {} storage buffers per shader stage, only {}",
+                needed, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneError::Parse { source, .. } | SceneError::MissingMtl { source, .. } => {
+                Some(source)
+            }
+            SceneError::Gltf { source, .. } => Some(source),
+            SceneError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/*
+* a scene in cpu memory, laid out the way the compute shader reads it
+* every mesh has its own bvh over its triangles (the blas)
+* and the scene is a list of instances of those meshes, each with a transform
+* and optionally a different material than the mesh was loaded with
+* Scene::bind_group builds the bvh over the instances (the tlas) and uploads it all
+*/
+pub struct Scene {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    // one more than the number of meshes, the last one marks where the buffers end
+    mesh_info: Vec<MeshInfo>,
+    mesh_names: Vec<String>,
+    mesh_bounds: Vec<bvh::Aabb>,
+    colors: Vec<Colors>,
+    material_names: Vec<String>,
+    blas_nodes: Vec<bvh::BvhNode>,
+    blas_triangles: Vec<BvhTriangle>,
+    instances: Vec<Instance>,
+    instance_bounds: Vec<bvh::Aabb>,
+    // what rays that leave the scene see, the old blue sky color if there is neither
+    environment: Option<Environment>,
+    sky: Option<Sky>,
+    punctual_lights: Vec<PunctualLight>,
+    textures: Vec<image::RgbaImage>,
+    // where the file wants the camera, with the vertical field of view in radians
+    camera: Option<(CameraPose, f32)>,
+}
+
+impl Scene {
+    pub(crate) fn new() -> Self {
+        Scene {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            mesh_info: vec![MeshInfo {
+                vertex_offset: 0,
+                index_offset: 0,
+                bvh_root: 0,
+                material: NO_MATERIAL,
+            }],
+            mesh_names: Vec::new(),
+            mesh_bounds: Vec::new(),
+            colors: Vec::new(),
+            material_names: Vec::new(),
+            blas_nodes: Vec::new(),
+            blas_triangles: Vec::new(),
+            instances: Vec::new(),
+            instance_bounds: Vec::new(),
+            environment: None,
+            sky: None,
+            punctual_lights: Vec::new(),
+            textures: Vec::new(),
+            camera: None,
+        }
+    }
+
+    pub(crate) fn add_material(&mut self, name: &str, colors: Colors) -> u32 {
+        self.colors.push(colors);
+        self.material_names.push(name.to_string());
+        (self.colors.len() - 1) as u32
+    }
+
+    pub(crate) fn add_texture(&mut self, texture: image::RgbaImage) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+
+    /*
+     * add a mesh and build its blas, it is not visible until it is instanced
+     * a trailing partial triangle is left out, so index_offset / 3 is always the first blas triangle
+     * returns None if there are no triangles, an index is past the vertices
+     * or the buffers would overflow a u32
+     */
+    pub(crate) fn add_mesh(&mut self, name: &str, mesh: &MeshData, material: u32) -> Option<u32> {
+        let MeshData {
+            positions,
+            normals,
+            texcoords,
+            colors,
+            indices,
+        } = mesh;
+        let indices = &indices[..indices.len() / 3 * 3];
+        if indices.len() < 3 || indices.iter().any(|&i| i as usize >= positions.len()) {
+            return None;
+        }
+        let end = *self.mesh_info.last().unwrap();
+        let vertex_end = u32::try_from(positions.len())
+            .ok()
+            .and_then(|n| end.vertex_offset.checked_add(n))?;
+        let index_end = u32::try_from(indices.len())
+            .ok()
+            .and_then(|n| end.index_offset.checked_add(n))?;
+        let triangle_offset = u32::try_from(self.blas_triangles.len()).ok()?;
+
+        let bounds = indices
+            .chunks_exact(3)
+            .map(|t| bvh::Aabb::from_points(t.iter().map(|&i| Vec3::from(positions[i as usize]))))
+            .collect::<Vec<bvh::Aabb>>();
+        let blas = bvh::build(&bounds);
+        self.mesh_bounds.push(blas.bounds());
+        self.blas_triangles.extend(blas.order.iter().map(|&t| {
+            let t = t as usize * 3;
+            BvhTriangle {
+                indices: [
+                    end.vertex_offset + indices[t],
+                    end.vertex_offset + indices[t + 1],
+                    end.vertex_offset + indices[t + 2],
+                ],
+                _padding: 0,
+            }
+        }));
+        let bvh_root = blas.append_to(&mut self.blas_nodes, triangle_offset);
+
+        self.vertices
+            .extend(positions.iter().enumerate().map(|(i, &position)| {
+                let [u, v] = texcoords.get(i).copied().unwrap_or_default();
+                Vertex {
+                    position,
+                    u,
+                    normal: normals.get(i).copied().unwrap_or_default(),
+                    v,
+                    color: colors.get(i).copied().unwrap_or([1.0; 3]),
+                    _padding: 0,
+                }
+            }));
+        self.indices.extend_from_slice(indices);
+
+        let last = self.mesh_info.len() - 1;
+        self.mesh_info[last].bvh_root = bvh_root;
+        self.mesh_info[last].material = material;
+        self.mesh_info.push(MeshInfo {
+            vertex_offset: vertex_end,
+            index_offset: index_end,
+            bvh_root: 0,
+            material: NO_MATERIAL,
+        });
+        self.mesh_names.push(name.to_string());
+        Some(last as u32)
+    }
+
+    /*
+     * place a mesh in the world, material overrides the one the mesh was loaded with
+     * panics if the mesh or material does not exist
+     */
+    pub fn add_instance(&mut self, mesh: u32, transform: Affine3A, material: Option<u32>) -> u32 {
+        assert!(mesh < self.num_meshes(), "mesh {} does not exist", mesh);
+        if let Some(material) = material {
+            assert!(
+                (material as usize) < self.colors.len(),
+                "material {} does not exist",
+                material
+            );
+        }
+        let rows = |m: Affine3A| {
+            let m = glam::Mat4::from(m).transpose();
+            [
+                m.x_axis.to_array(),
+                m.y_axis.to_array(),
+                m.z_axis.to_array(),
+            ]
+        };
+        self.instances.push(Instance {
+            object_to_world: rows(transform),
+            world_to_object: rows(transform.inverse()),
+            mesh,
+            material: material.unwrap_or(NO_MATERIAL),
+            first_light: NO_LIGHT,
+            _padding: 0,
+        });
+        self.instance_bounds
+            .push(self.mesh_bounds[mesh as usize].transform(&transform));
+        (self.instances.len() - 1) as u32
+    }
+
+    pub fn mesh_id(&self, name: &str) -> Option<u32> {
+        self.mesh_names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| i as u32)
+    }
+
+    pub fn material_id(&self, name: &str) -> Option<u32> {
+        self.material_names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| i as u32)
+    }
+
+    pub fn num_meshes(&self) -> u32 {
+        (self.mesh_info.len() - 1) as u32
+    }
+
+    pub fn num_instances(&self) -> u32 {
+        self.instances.len() as u32
+    }
+
+    // instances with an emissive material
+    pub fn num_lights(&self) -> u32 {
+        self.instances
+            .iter()
+            .filter(|i| {
+                let c = self.colors[self.instance_material(i) as usize].ambient_color;
+                c[0] > 0.0 || c[1] > 0.0 || c[2] > 0.0
+            })
+            .count() as u32
+    }
+
+    // light the scene with an environment map, replaces the one or the sky set before
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = Some(environment);
+        self.sky = None;
+    }
+
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    // light the scene with a daylight sky, replaces the one or the environment map set before
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.sky = Some(sky);
+        self.environment = None;
+    }
+
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    pub fn add_punctual_light(&mut self, light: PunctualLight) {
+        self.punctual_lights.push(light);
+    }
+
+    pub fn set_camera(&mut self, pose: CameraPose, vertical_fov: f32) {
+        self.camera = Some((pose, vertical_fov));
+    }
+
+    // the camera the scene file came with, if it had one
+    pub fn camera(&self) -> Option<(CameraPose, f32)> {
+        self.camera
+    }
+
+    pub fn num_punctual_lights(&self) -> u32 {
+        self.punctual_lights.len() as u32
+    }
+
+    // the punctual lights and the sun of the sky if there is one
+    fn gpu_punctual_lights(&self) -> Vec<GpuPunctualLight> {
+        self.punctual_lights
+            .iter()
+            .copied()
+            .chain(self.sky.and_then(|sky| sky.sun()))
+            .map(|light| GpuPunctualLight::from(&light))
+            .collect()
+    }
+
+    // unique triangles, instancing a mesh again does not add to this
+    pub fn num_triangles(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    /*
+     * every triangle of every emissive instance, moved into world space
+     * they are in the order of the blas triangles, so the shader can find the light it hit
+     * from the first light of the instance and the triangle index
+     * also returns the first light of every instance, NO_LIGHT if it does not emit
+     */
+    fn light_triangles(&self) -> (Vec<LightTriangle>, Vec<u32>) {
+        let mut triangles = Vec::new();
+        let mut first_lights = Vec::with_capacity(self.instances.len());
+        for inst in &self.instances {
+            let material = self.instance_material(inst);
+            let emission = self.colors[material as usize].ambient_color;
+            if light::luminance(emission) <= 0.0 {
+                first_lights.push(NO_LIGHT);
+                continue;
+            }
+            first_lights.push(triangles.len() as u32);
+            let to_world = |v: u32| {
+                let p = Vec3::from(self.vertices[v as usize].position).extend(1.0);
+                let m = inst.object_to_world;
+                Vec3::new(p.dot(m[0].into()), p.dot(m[1].into()), p.dot(m[2].into()))
+            };
+            // there is one blas triangle for every three indices
+            let start = self.mesh_info[inst.mesh as usize].index_offset as usize / 3;
+            let end = self.mesh_info[inst.mesh as usize + 1].index_offset as usize / 3;
+            for (i, tri) in self.blas_triangles[start..end].iter().enumerate() {
+                let vertices = tri.indices.map(to_world);
+                let area = 0.5
+                    * (vertices[1] - vertices[0])
+                        .cross(vertices[2] - vertices[0])
+                        .length();
+                triangles.push(LightTriangle {
+                    vertices,
+                    triangle: (start + i) as u32,
+                    material,
+                    power: area * light::luminance(emission),
+                });
+            }
+        }
+        (triangles, first_lights)
+    }
+
+    fn instance_material(&self, instance: &Instance) -> u32 {
+        if instance.material == NO_MATERIAL {
+            self.mesh_info[instance.mesh as usize].material
+        } else {
+            instance.material
+        }
+    }
+
+    // we build the tlas, create the buffers and return their bindgroup so that it can be used for whatever
+    pub(crate) fn bind_group(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Result<wgpu::BindGroup, SceneError> {
+        let (light_triangles, first_lights) = self.light_triangles();
+        // built like the blas, so it is no deeper than the stack closest_intersection walks it with
+        let tlas = bvh::build(&self.instance_bounds);
+        let instances = tlas
+            .order
+            .iter()
+            .map(|&i| Instance {
+                first_light: first_lights[i as usize],
+                ..self.instances[i as usize]
+            })
+            .collect::<Vec<Instance>>();
+
+        let mut light_tree = light::build(&light_triangles);
+        let num_lights = light_tree.lights.len() as u32;
+        // buffers can not be empty, the shader never reads these
+        if num_lights == 0 {
+            light_tree.nodes.push(bytemuck::Zeroable::zeroed());
+            light_tree.lights.push(bytemuck::Zeroable::zeroed());
+        }
+        if light_tree.slots.is_empty() {
+            light_tree.slots.push(light::NO_SLOT);
+        }
+
+        let (env_pixels, env_cdf) = match &self.environment {
+            Some(env) => env.sampling_data(),
+            // never read, buffers just can not be empty
+            None => (vec![[0.0; 4]], vec![0.0]),
+        };
+
+        let compute_info = ComputeInfo {
+            num_meshes: self.num_meshes(),
+            num_lights,
+            env_width: self.environment.as_ref().map_or(0, |e| e.width()),
+            env_height: self.environment.as_ref().map_or(0, |e| e.height()),
+            env_rotation: self.environment.as_ref().map_or(0.0, |e| e.rotation),
+            env_intensity: self.environment.as_ref().map_or(0.0, |e| e.intensity),
+            num_punctual_lights: self.gpu_punctual_lights().len() as u32,
+            _padding: 0,
+        };
+        let sky = self
+            .sky
+            .map_or(bytemuck::Zeroable::zeroed(), |sky| sky.gpu());
+
+        // a big scan or environment map would otherwise be a validation panic in wgpu
+        let limits = device.limits();
+        let buffer = |label: &str, contents: &[u8], usage: wgpu::BufferUsages| {
+            let binding_limit = if usage.contains(wgpu::BufferUsages::STORAGE) {
+                limits.max_storage_buffer_binding_size
+            } else {
+                limits.max_uniform_buffer_binding_size
+            };
+            let limit = limits.max_buffer_size.min(binding_limit as u64);
+            if contents.len() as u64 > limit {
+                return Err(SceneError::TooLarge {
+                    buffer: label.to_string(),
+                    size: contents.len() as u64,
+                    limit,
+                });
+            }
+            Ok(
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents,
+                    usage,
+                }),
+            )
+        };
+        let storage = wgpu::BufferUsages::STORAGE;
+        let buffers = [
+            buffer(
+                "Vertex Buffer",
+                bytemuck::cast_slice(&self.vertices),
+                storage,
+            )?,
+            buffer("Index Buffer", bytemuck::cast_slice(&self.indices), storage)?,
+            buffer(
+                "Mesh Info Buffer",
+                bytemuck::cast_slice(&self.mesh_info),
+                storage,
+            )?,
+            buffer("Colors Buffer", bytemuck::cast_slice(&self.colors), storage)?,
+            buffer(
+                "Compute Info Buffer",
+                bytemuck::cast_slice(&[compute_info]),
+                wgpu::BufferUsages::UNIFORM,
+            )?,
+            buffer(
+                "BLAS Node Buffer",
+                bytemuck::cast_slice(&self.blas_nodes),
+                storage,
+            )?,
+            buffer(
+                "BLAS Triangle Buffer",
+                bytemuck::cast_slice(&self.blas_triangles),
+                storage,
+            )?,
+            buffer(
+                "TLAS Node Buffer",
+                bytemuck::cast_slice(&tlas.nodes),
+                storage,
+            )?,
+            buffer("Instance Buffer", bytemuck::cast_slice(&instances), storage)?,
+            buffer(
+                "Light Buffer",
+                bytemuck::cast_slice::<Light, u8>(&light_tree.lights),
+                storage,
+            )?,
+            buffer(
+                "Light Node Buffer",
+                bytemuck::cast_slice::<LightNode, u8>(&light_tree.nodes),
+                storage,
+            )?,
+            buffer(
+                "Light Slot Buffer",
+                bytemuck::cast_slice(&light_tree.slots),
+                storage,
+            )?,
+            buffer(
+                "Environment Pixel Buffer",
+                bytemuck::cast_slice(&env_pixels),
+                storage,
+            )?,
+            buffer(
+                "Environment CDF Buffer",
+                bytemuck::cast_slice(&env_cdf),
+                storage,
+            )?,
+            buffer(
+                "Sky Buffer",
+                bytemuck::cast_slice::<GpuSky, u8>(&[sky]),
+                wgpu::BufferUsages::UNIFORM,
+            )?,
+        ];
+
+        let (texture_view, texture_rects) = texture::create_array(device, queue, &self.textures)?;
+        let texture_rect_buffer = buffer(
+            "Texture Rect Buffer",
+            bytemuck::cast_slice(&texture_rects),
+            wgpu::BufferUsages::UNIFORM,
+        )?;
+
+        // the bindings are in the same order as the buffers, see bind_group_layout
+        let mut entries = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, b)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: b.as_entire_binding(),
+            })
+            .collect::<Vec<wgpu::BindGroupEntry>>();
+        let first_texture_binding = entries.len() as u32;
+        entries.push(wgpu::BindGroupEntry {
+            binding: first_texture_binding,
+            resource: wgpu::BindingResource::TextureView(&texture_view),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: first_texture_binding + 1,
+            resource: texture_rect_buffer.as_entire_binding(),
+        });
+
+        Ok(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("compute_bind_group"),
+        }))
+    }
+
+    // the punctual lights get their own bind group, see punctual::bind_group_layout
+    pub(crate) fn punctual_bind_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+    ) -> wgpu::BindGroup {
+        let mut lights = self.gpu_punctual_lights();
+        // buffers can not be empty, the shader never reads this one
+        if lights.is_empty() {
+            lights.push(bytemuck::Zeroable::zeroed());
+        }
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Punctual Light Buffer"),
+            contents: bytemuck::cast_slice(&lights),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("punctual_bind_group"),
+        })
+    }
+}
+
+fn storage_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    buffer_entry(
+        binding,
+        wgpu::BufferBindingType::Storage { read_only: true },
+    )
+}
+
+fn buffer_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
 }
 
-pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+pub(crate) fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
     device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         label: Some("compute_bind_group_layout"),
         entries: &[
             // Vertices
-            wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
+            storage_entry(0),
             // Indices
-            wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
+            storage_entry(1),
             // MeshInfo
-            wgpu::BindGroupLayoutEntry {
-                binding: 2,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
+            storage_entry(2),
             // Colors
-            wgpu::BindGroupLayoutEntry {
-                binding: 3,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
+            storage_entry(3),
             // ComputeInfo
+            buffer_entry(4, wgpu::BufferBindingType::Uniform),
+            // BlasNodes
+            storage_entry(5),
+            // BlasTriangles
+            storage_entry(6),
+            // TlasNodes
+            storage_entry(7),
+            // Instances
+            storage_entry(8),
+            // Lights
+            storage_entry(9),
+            // LightNodes
+            storage_entry(10),
+            // LightSlots
+            storage_entry(11),
+            // EnvironmentPixels
+            storage_entry(12),
+            // EnvironmentCdf
+            storage_entry(13),
+            // Sky
+            buffer_entry(14, wgpu::BufferBindingType::Uniform),
+            // Textures
             wgpu::BindGroupLayoutEntry {
-                binding: 4,
+                binding: 15,
                 visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false,
                 },
                 count: None,
             },
+            // TextureRects
+            buffer_entry(16, wgpu::BufferBindingType::Uniform),
         ],
     })
 }
@@ -99,153 +886,436 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
 * load model from .obj file
 * a model can contain several meshes
 * a mesh will be one or more connected triangle faces
-* we assume the mesh uses a material (mtl file)
-* we assume we are not using textures, only uniformly colored meshes
-* we use only the ambient and diffuse color of the mesh
-* the ambient color is emitted light
+* we assume the mesh uses a material (mtl file), otherwise we return a SceneError
+* we use the ambient, diffuse and specular color and the specular exponent of the mesh
+* map_Kd, map_Ka and map_Ks multiply these colors, map_Bump is a height map scaled by -bm
+* textures that can not be read are left out with a warning
+* the ambient color is the radiance the surface emits, so lights in the mtl are in physical units
 * the diffuse color is the 100% diffusely reflected color of the mesh
-* we create one vertex and one index buffer for the whole obj file
-* we create a meshinfo buffer with the offsets for each section of the vertex and index buffers
-* the meshinfo buffer also contains the color info for each mesh
-* we lastly create a computeinfo buffer with the length of the meshinfo buffer
-* we return the bindgroup of the 4 buffers so that it can be used for whatever
+* the specular color is the glossy reflection straight on, Ns decides how glossy
+* d, Tr and illum decide how much light goes through as through glass, Ni is the index of refraction
+* glass objects need their faces wound counterclockwise seen from outside so we know when we enter
+* every object in the file becomes a mesh with one instance that leaves it where it is
+* more instances can be added afterwards with Scene::add_instance
 */
-pub fn load<P: AsRef<Path>>(
-    device: &wgpu::Device,
-    layout: &wgpu::BindGroupLayout,
-    path: P,
-) -> Result<wgpu::BindGroup, tobj::LoadError> {
-    let (obj_models, obj_materials) = tobj::load_obj(path.as_ref(), &tobj::GPU_LOAD_OPTIONS)?;
-    let obj_materials = obj_materials?;
-    let vertices = obj_models
-        .iter()
-        .flat_map(|m| {
-            (0..m.mesh.positions.len() / 3).map(|i| Vertex {
-                position: [
-                    m.mesh.positions[i * 3],
-                    m.mesh.positions[i * 3 + 1],
-                    m.mesh.positions[i * 3 + 2],
-                ],
-                _padding: 0,
-            })
-        })
-        .collect::<Vec<Vertex>>();
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let (obj_models, obj_materials) =
+        tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS).map_err(|source| SceneError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let obj_materials = obj_materials.map_err(|source| SceneError::MissingMtl {
+        path: path.to_path_buf(),
+        source,
+    })?;
 
-    let mut mesh_info = Vec::new();
-    let mut colors = Vec::new();
-    // could do this as well functionally with something like scan()
-    let mut vertex_offset = 0;
-    let mut index_offset = 0;
+    let mut scene = Scene::new();
+    // tobj material id to our material id, only for the materials that are used
+    let mut material_ids = vec![None; obj_materials.len()];
+    // materials often share textures, only load them once
+    let mut texture_ids = HashMap::new();
     for m in &obj_models {
-        let mat_id = m.mesh.material_id.expect("mesh missing material id");
-        mesh_info.push(MeshInfo {
-            vertex_offset,
-            index_offset,
-        });
+        if m.mesh.indices.is_empty() {
+            log::warn!("{}: object '{}' has no faces", path.display(), m.name);
+            continue;
+        }
+        let mat_id = m
+            .mesh
+            .material_id
+            .filter(|&id| id < obj_materials.len())
+            .ok_or_else(|| SceneError::MissingMaterial {
+                path: path.to_path_buf(),
+                object: m.name.clone(),
+            })?;
+        let material = match material_ids[mat_id] {
+            Some(id) => id,
+            None => {
+                let obj_material = &obj_materials[mat_id];
+                let mut colors = material_colors(path, obj_material)?;
+                let mut texture = |map: &Option<String>| {
+                    map.as_deref().map_or((NO_TEXTURE, 1.0), |map| {
+                        load_texture(path, map, &mut scene, &mut texture_ids)
+                    })
+                };
+                colors.diffuse_texture = texture(&obj_material.diffuse_texture).0;
+                colors.ambient_texture = texture(&obj_material.ambient_texture).0;
+                colors.specular_texture = texture(&obj_material.specular_texture).0;
+                (colors.bump_texture, colors.bump_scale) = texture(&obj_material.normal_texture);
+                let id = scene.add_material(&obj_material.name, colors);
+                material_ids[mat_id] = Some(id);
+                id
+            }
+        };
 
-        colors.push(Colors {
-            ambient_color: obj_materials[mat_id]
-                .ambient
-                .expect("mtl missing ambient color"),
-            diffuse_color: obj_materials[mat_id]
-                .diffuse
-                .expect("mtl missing diffuse color"),
-            _padding: 0,
-            _padding2: 0,
+        let mut data = MeshData {
+            positions: m
+                .mesh
+                .positions
+                .chunks_exact(3)
+                .map(|p| [p[0], p[1], p[2]])
+                .collect(),
+            normals: m
+                .mesh
+                .normals
+                .chunks_exact(3)
+                .map(|n| [n[0], n[1], n[2]])
+                .collect(),
+            // obj has v going up the image, the gpu down
+            texcoords: m
+                .mesh
+                .texcoords
+                .chunks_exact(2)
+                .map(|t| [t[0], 1.0 - t[1]])
+                .collect(),
+            colors: Vec::new(),
+            indices: m.mesh.indices.clone(),
+        };
+        if data.normals.is_empty() {
+            data = smooth_normals(&data);
+        }
+        let mesh =
+            scene
+                .add_mesh(&m.name, &data, material)
+                .ok_or_else(|| SceneError::IndexOverflow {
+                    path: path.to_path_buf(),
+                    object: m.name.clone(),
+                })?;
+        scene.add_instance(mesh, Affine3A::IDENTITY, None);
+    }
+
+    if scene.num_meshes() == 0 {
+        return Err(SceneError::EmptyScene {
+            path: path.to_path_buf(),
         });
-        vertex_offset += <usize as TryInto<u32>>::try_into(m.mesh.positions.len() / 3)
-            .expect("too many vertices");
-        index_offset +=
-            <usize as TryInto<u32>>::try_into(m.mesh.indices.len()).expect("too many indices");
     }
 
-    mesh_info.push(MeshInfo {
-        vertex_offset,
-        index_offset,
-    });
+    log::info!(
+        "{}: loaded {} meshes with {} triangles and {} blas nodes",
+        path.display(),
+        scene.num_meshes(),
+        scene.num_triangles(),
+        scene.blas_nodes.len()
+    );
 
-    colors.push(Colors {
-        ambient_color: [0.0, 0.0, 0.0],
-        diffuse_color: [0.0, 0.0, 0.0],
-        _padding: 0,
-        _padding2: 0,
-    });
+    Ok(scene)
+}
+/*
+* a scene holding one mesh with one instance, for the files that are nothing but triangles
+* the mesh gets a grey diffuse material, or a white one that the vertex colors tint
+*/
+pub(crate) fn single_mesh_scene(path: &Path, mut data: MeshData) -> Result<Scene, SceneError> {
+    if data.indices.len() < 3 {
+        return Err(SceneError::EmptyScene {
+            path: path.to_path_buf(),
+        });
+    }
+    if data.normals.is_empty() {
+        data = smooth_normals(&data);
+    }
+    let name = path
+        .file_stem()
+        .map_or_else(|| "mesh".to_string(), |s| s.to_string_lossy().into_owned());
+    let mut scene = Scene::new();
+    let material = scene.add_material(
+        &name,
+        Colors {
+            diffuse_color: if data.colors.is_empty() {
+                DEFAULT_DIFFUSE
+            } else {
+                [1.0; 3]
+            },
+            ..Default::default()
+        },
+    );
+    let mesh = scene
+        .add_mesh(&name, &data, material)
+        .ok_or_else(|| SceneError::IndexOverflow {
+            path: path.to_path_buf(),
+            object: name,
+        })?;
+    scene.add_instance(mesh, Affine3A::IDENTITY, None);
 
-    let indices = obj_models
-        .iter()
-        .flat_map(|m| m.mesh.indices.clone()) // should avoid unnecessary clone
-        .collect::<Vec<u32>>();
+    log::info!(
+        "{}: loaded {} vertices and {} triangles",
+        path.display(),
+        data.positions.len(),
+        scene.num_triangles()
+    );
+    Ok(scene)
+}
 
-    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Vertex Buffer"),
-        contents: bytemuck::cast_slice(&vertices),
-        usage: wgpu::BufferUsages::STORAGE,
-    });
+/*
+* vertex normals for a mesh that has none, every corner of a face averages the normals
+* of the faces around its position that are within SMOOTH_ANGLE of the face, weighted by area
+* so curved surfaces look smooth and sharp edges stay sharp
+* vertices are split where their corners end up with different normals
+* corners of faces without area get a zero normal, the shader shades those flat
+*/
+pub(crate) fn smooth_normals(mesh: &MeshData) -> MeshData {
+    let MeshData {
+        positions,
+        texcoords,
+        colors,
+        indices,
+        ..
+    } = mesh;
+    // like add_mesh only whole triangles
+    let indices = &indices[..indices.len() / 3 * 3];
+    let face_normals = indices
+        .chunks_exact(3)
+        .map(|t| {
+            let [a, b, c] = [t[0], t[1], t[2]].map(|i| Vec3::from(positions[i as usize]));
+            (b - a).cross(c - a)
+        })
+        .collect::<Vec<Vec3>>();
+    // faces around every position, vertices that only differ in texcoords or colors share them
+    let key = |i: u32| positions[i as usize].map(f32::to_bits);
+    let mut faces_at = HashMap::<[u32; 3], Vec<usize>>::new();
+    for (corner, &i) in indices.iter().enumerate() {
+        faces_at.entry(key(i)).or_default().push(corner / 3);
+    }
 
-    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Index Buffer"),
-        contents: bytemuck::cast_slice(&indices),
-        usage: wgpu::BufferUsages::STORAGE,
-    });
+    let cos_smooth = SMOOTH_ANGLE.to_radians().cos();
+    let mut out = MeshData {
+        indices: Vec::with_capacity(indices.len()),
+        ..Default::default()
+    };
+    let mut vertex_ids = HashMap::<(u32, [u32; 3]), u32>::new();
+    for (corner, &i) in indices.iter().enumerate() {
+        let face = face_normals[corner / 3].normalize_or_zero();
+        let normal = faces_at[&key(i)]
+            .iter()
+            .map(|&f| face_normals[f])
+            .filter(|n| n.normalize_or_zero().dot(face) >= cos_smooth)
+            .sum::<Vec3>()
+            .normalize_or_zero()
+            .to_array();
+        let id = *vertex_ids
+            .entry((i, normal.map(f32::to_bits)))
+            .or_insert_with(|| {
+                out.positions.push(positions[i as usize]);
+                out.normals.push(normal);
+                if let Some(&t) = texcoords.get(i as usize) {
+                    out.texcoords.push(t);
+                }
+                if let Some(&c) = colors.get(i as usize) {
+                    out.colors.push(c);
+                }
+                out.positions.len() as u32 - 1
+            });
+        out.indices.push(id);
+    }
+    out
+}
 
-    let mesh_info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Mesh Info Buffer"),
-        contents: bytemuck::cast_slice(&mesh_info),
-        usage: wgpu::BufferUsages::STORAGE,
+/*
+* a material needs at least one of Ka and Kd to be usable
+* if only one of them is given we fall back to no emission or a grey diffuse color
+*/
+fn material_colors(path: &Path, material: &tobj::Material) -> Result<Colors, SceneError> {
+    if material.ambient.is_none() && material.diffuse.is_none() {
+        return Err(SceneError::MissingColors {
+            path: path.to_path_buf(),
+            material: material.name.clone(),
+        });
+    }
+    let ambient_color = material.ambient.unwrap_or_else(|| {
+        log::warn!(
+            "{}: material '{}' has no Ka, assuming it emits no light",
+            path.display(),
+            material.name
+        );
+        DEFAULT_AMBIENT
     });
-
-    let colors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Colors Buffer"),
-        contents: bytemuck::cast_slice(&colors),
-        usage: wgpu::BufferUsages::STORAGE,
+    let diffuse_color = material.diffuse.unwrap_or_else(|| {
+        log::warn!(
+            "{}: material '{}' has no Kd, using grey",
+            path.display(),
+            material.name
+        );
+        DEFAULT_DIFFUSE
     });
+    Ok(Colors {
+        ambient_color,
+        ior: material.optical_density.unwrap_or(DEFAULT_IOR),
+        diffuse_color,
+        transmission: transmission(path, material),
+        specular_color: material.specular.unwrap_or(DEFAULT_SPECULAR),
+        roughness: shininess_to_roughness(material.shininess.unwrap_or(0.0)),
+        transmission_color: material
+            .unknown_param
+            .get("Tf")
+            .and_then(|tf| parse_color(tf))
+            .unwrap_or(DEFAULT_TRANSMISSION_COLOR),
+        ..Default::default()
+    })
+}
 
-    let compute_info = ComputeInfo {
-        // -1 because the last mesh is a dummy to show where we end
-        num_meshes: (mesh_info.len() - 1).try_into().expect("too many meshes"),
-        num_lights: colors
-            .iter()
-            .filter(|x| {
-                x.ambient_color[0] > 0.0 || x.ambient_color[1] > 0.0 || x.ambient_color[2] > 0.0
-            })
-            .count() as u32,
+/*
+* the texture layer of a map line from the mtl file and its bump multiplier
+* file names are relative to the obj file
+*/
+fn load_texture(
+    path: &Path,
+    map: &str,
+    scene: &mut Scene,
+    texture_ids: &mut HashMap<PathBuf, u32>,
+) -> (u32, f32) {
+    let (file, bump_scale) = texture::parse_map(map);
+    let file = path.parent().unwrap_or(Path::new("")).join(file);
+    if let Some(&id) = texture_ids.get(&file) {
+        return (id, bump_scale);
+    }
+    let id = match texture::load(&file) {
+        Ok(image) => scene.add_texture(image),
+        Err(e) => {
+            log::warn!(
+                "{}: failed to load texture {}, leaving it out: {}",
+                path.display(),
+                file.display(),
+                e
+            );
+            NO_TEXTURE
+        }
     };
+    texture_ids.insert(file, id);
+    (id, bump_scale)
+}
 
-    let compute_info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Compute Info Buffer"),
-        contents: bytemuck::cast_slice(&[compute_info]),
-        usage: wgpu::BufferUsages::UNIFORM,
+/*
+* how much of the light goes through the surface instead of being reflected by it
+* d (dissolve) is opacity and Tr is its inverse, d wins if both are there
+* illum 6 and 7 are the refractive illumination models, exporters often leave d at 1
+* for those, so they count as fully transmissive unless d or Tr say otherwise
+* illum 4 is glass that reflects but is only as see through as d makes it
+* for every other model d is plain opacity, which we have no way to show, so they stay opaque
+*/
+fn transmission(path: &Path, material: &tobj::Material) -> f32 {
+    if !matches!(material.illumination_model, Some(4 | 6 | 7)) {
+        return 0.0;
+    }
+    let tr = material.unknown_param.get("Tr").and_then(|tr| {
+        let tr = tr.trim().parse::<f32>().ok();
+        if tr.is_none() {
+            log::warn!(
+                "{}: material '{}' has an invalid Tr, ignoring it",
+                path.display(),
+                material.name
+            );
+        }
+        tr
     });
+    let transmission = match (material.dissolve, tr) {
+        (Some(d), _) => 1.0 - d,
+        (None, Some(tr)) => tr,
+        (None, None) => 0.0,
+    };
+    let refractive = matches!(material.illumination_model, Some(6 | 7));
+    if refractive && transmission <= 0.0 {
+        return 1.0;
+    }
+    transmission.clamp(0.0, 1.0)
+}
 
-    Ok(device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout,
-        entries: &[
-            // Vertices
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: vertex_buffer.as_entire_binding(),
-            },
-            // Indices
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: index_buffer.as_entire_binding(),
-            },
-            // MeshInfo
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: mesh_info_buffer.as_entire_binding(),
-            },
-            // Colors
-            wgpu::BindGroupEntry {
-                binding: 3,
-                resource: colors_buffer.as_entire_binding(),
-            },
-            // ComputeInfo
-            wgpu::BindGroupEntry {
-                binding: 4,
-                resource: compute_info_buffer.as_entire_binding(),
+fn parse_color(s: &str) -> Option<[f32; 3]> {
+    let mut it = s.split_whitespace().map(|x| x.parse::<f32>().ok());
+    Some([it.next()??, it.next()??, it.next()??])
+}
+
+/*
+* convert a blinn-phong exponent (Ns) to the ggx alpha that gives a similar highlight
+* http://simonstechblog.blogspot.com/2011/12/microfacet-brdf.html
+* Ns 0 is completely rough, the usual maximum of 1000 is almost a mirror
+*/
+fn shininess_to_roughness(ns: f32) -> f32 {
+    (2.0 / (ns.max(0.0) + 2.0)).sqrt().max(MIN_ROUGHNESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(illum: u8, dissolve: Option<f32>) -> tobj::Material {
+        tobj::Material {
+            illumination_model: Some(illum),
+            dissolve,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn transmission_from_illum_and_dissolve() {
+        let path = Path::new("test.mtl");
+        // illum 4 is only as transparent as d says
+        assert_eq!(transmission(path, &material(4, Some(1.0))), 0.0);
+        assert_eq!(transmission(path, &material(4, Some(0.25))), 0.75);
+        assert_eq!(transmission(path, &material(4, None)), 0.0);
+        // 6 and 7 refract even when d was left at 1
+        assert_eq!(transmission(path, &material(6, Some(1.0))), 1.0);
+        assert_eq!(transmission(path, &material(7, None)), 1.0);
+        assert_eq!(transmission(path, &material(7, Some(0.5))), 0.5);
+        // everything else is opaque, d there is opacity and not glass
+        assert_eq!(transmission(path, &material(2, Some(1.0))), 0.0);
+        assert_eq!(transmission(path, &material(2, Some(0.5))), 0.0);
+        assert_eq!(transmission(path, &material(9, Some(0.25))), 0.0);
+    }
+
+    fn square(x: f32, indices: Vec<u32>) -> MeshData {
+        MeshData {
+            positions: vec![
+                [x, 0.0, 0.0],
+                [x + 1.0, 0.0, 0.0],
+                [x, 1.0, 0.0],
+                [x + 1.0, 1.0, 0.0],
+            ],
+            indices,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn partial_triangles_do_not_shift_later_meshes() {
+        let mut scene = Scene::new();
+        let grey = scene.add_material("grey", Colors::default());
+        let lamp = scene.add_material(
+            "lamp",
+            Colors {
+                ambient_color: [1.0; 3],
+                ..Default::default()
             },
-        ],
-        label: Some("compute_bind_group"),
-    }))
+        );
+        // a whole triangle and two corners of another, twice, so the corners add up to a triangle
+        for name in ["floor", "wall"] {
+            scene
+                .add_mesh(name, &square(0.0, vec![0, 1, 2, 1, 3]), grey)
+                .unwrap();
+        }
+        let light = scene
+            .add_mesh("light", &square(5.0, vec![0, 1, 2]), lamp)
+            .unwrap();
+        assert_eq!(scene.num_triangles(), 3);
+        scene.add_instance(light, Affine3A::IDENTITY, None);
+
+        let (triangles, first_lights) = scene.light_triangles();
+        assert_eq!(first_lights, [0]);
+        assert_eq!(triangles.len(), 1);
+        assert_eq!(triangles[0].triangle, 2);
+        assert!(triangles[0].vertices.iter().all(|v| v.x >= 5.0));
+    }
+
+    #[test]
+    fn indices_past_the_vertices() {
+        let mut scene = Scene::new();
+        let grey = scene.add_material("grey", Colors::default());
+        assert_eq!(
+            scene.add_mesh("bad", &square(0.0, vec![0, 1, 4]), grey),
+            None
+        );
+        assert_eq!(
+            scene.add_mesh("short", &square(0.0, vec![0, 1]), grey),
+            None
+        );
+        assert_eq!(scene.num_meshes(), 0);
+    }
 }
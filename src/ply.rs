@@ -0,0 +1,426 @@
+use crate::model::{MeshData, Scene, SceneError, single_mesh_scene};
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(Scalar),
+    // type of the length, then of the items
+    List(Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        Some(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // what a color channel of this type is when it is fully on
+    fn color_max(self) -> f64 {
+        match self {
+            Scalar::I8 => 127.0,
+            Scalar::U8 => 255.0,
+            Scalar::I16 => 32767.0,
+            Scalar::U16 => 65535.0,
+            Scalar::I32 => 2147483647.0,
+            Scalar::U32 => 4294967295.0,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+// the data after the header, every value is read as f64 which holds all the types exactly
+struct Body<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Body<'_> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let start = self.pos
+                + self.bytes[self.pos..]
+                    .iter()
+                    .position(|b| !b.is_ascii_whitespace())
+                    .ok_or("the file ends before all elements are read")?;
+            let end = self.bytes[start..]
+                .iter()
+                .position(|b| b.is_ascii_whitespace())
+                .map_or(self.bytes.len(), |n| start + n);
+            self.pos = end;
+            let word = String::from_utf8_lossy(&self.bytes[start..end]);
+            return word
+                .parse::<f64>()
+                .map_err(|_| format!("'{}' is not a number", word));
+        }
+
+        let size = scalar.size();
+        let mut b = self
+            .bytes
+            .get(self.pos..self.pos + size)
+            .ok_or("the file ends before all elements are read")?
+            .to_vec();
+        self.pos += size;
+        if self.format == Format::BigEndian {
+            b.reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => b[0] as i8 as f64,
+            Scalar::U8 => b[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(b.try_into().unwrap()),
+        })
+    }
+
+    // a property that is a list gives all its items
+    fn read_property(&mut self, property: &Property, items: &mut Vec<f64>) -> Result<(), String> {
+        items.clear();
+        match *property {
+            Property::Scalar(scalar) => items.push(self.read(scalar)?),
+            Property::List(length, item) => {
+                let length = self.read(length)?;
+                for _ in 0..length as usize {
+                    items.push(self.read(item)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+ * load an ascii or binary .ply file as a single mesh
+ * vertices give their position and optionally a normal (nx ny nz) and a color (red green blue),
+ * colors are srgb like an image, integers go from 0 to their maximum and floats from 0 to 1
+ * faces are the vertex_indices lists, polygons are split into a fan of triangles
+ * every other element and property is skipped, without normals they are computed
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(&bytes)
+        .map_err(|message| SceneError::Malformed {
+            path: path.to_path_buf(),
+            message,
+        })
+        .and_then(|data| single_mesh_scene(path, data))
+}
+
+fn parse(bytes: &[u8]) -> Result<MeshData, String> {
+    let (format, elements, body) = parse_header(bytes)?;
+    let mut body = Body {
+        format,
+        bytes: &bytes[body..],
+        pos: 0,
+    };
+
+    let mut data = MeshData::default();
+    let mut items = Vec::new();
+    for element in &elements {
+        let find = |names: &[&str]| {
+            element
+                .properties
+                .iter()
+                .position(|(n, _)| names.contains(&n.as_str()))
+        };
+        match element.name.as_str() {
+            "vertex" => {
+                let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+                let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+                let color = [
+                    find(&["red", "diffuse_red"]),
+                    find(&["green", "diffuse_green"]),
+                    find(&["blue", "diffuse_blue"]),
+                ];
+                let Some([x, y, z]) = all(position) else {
+                    return Err("vertices have no x, y and z".to_string());
+                };
+                let normal = all(normal);
+                let color = all(color);
+
+                let mut values = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, (_, property)) in values.iter_mut().zip(&element.properties) {
+                        body.read_property(property, &mut items)?;
+                        *value = items.first().copied().unwrap_or_default();
+                    }
+                    data.positions.push([x, y, z].map(|i| values[i] as f32));
+                    if let Some(n) = normal {
+                        data.normals.push(n.map(|i| values[i] as f32));
+                    }
+                    if let Some(c) = color {
+                        data.colors.push(c.map(|i| {
+                            let max = match element.properties[i].1 {
+                                Property::Scalar(s) => s.color_max(),
+                                Property::List(..) => 1.0,
+                            };
+                            srgb_to_linear((values[i] / max).clamp(0.0, 1.0) as f32)
+                        }));
+                    }
+                }
+            }
+            "face" => {
+                let indices = find(&["vertex_indices", "vertex_index"])
+                    .ok_or("faces have no vertex_indices")?;
+                for _ in 0..element.count {
+                    for (i, (_, property)) in element.properties.iter().enumerate() {
+                        body.read_property(property, &mut items)?;
+                        if i != indices {
+                            continue;
+                        }
+                        for k in 1..items.len().saturating_sub(1) {
+                            data.indices
+                                .extend([items[0], items[k], items[k + 1]].map(|v| v as u32));
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for (_, property) in &element.properties {
+                        body.read_property(property, &mut items)?;
+                    }
+                }
+            }
+        }
+    }
+
+    let num_vertices = data.positions.len();
+    if let Some(&i) = data.indices.iter().find(|&&i| i as usize >= num_vertices) {
+        return Err(format!(
+            "a face uses vertex {} but there are only {}",
+            i, num_vertices
+        ));
+    }
+    Ok(data)
+}
+
+// the format, the elements and where the body starts
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    if !bytes.starts_with(b"ply") {
+        return Err("not a ply file".to_string());
+    }
+    let mut format = None;
+    let mut elements = Vec::<Element>::new();
+    let mut pos = 0;
+    loop {
+        let end = bytes[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("the header has no end_header")?;
+        let line = String::from_utf8_lossy(&bytes[pos..pos + end]);
+        pos += end + 1;
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", f, _] => {
+                format = Some(match *f {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(format!("unknown format '{}'", f)),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("'{}' is not an element count", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", length, item, name] => {
+                let scalar = |s: &str| Scalar::parse(s).ok_or(format!("unknown type '{}'", s));
+                elements
+                    .last_mut()
+                    .ok_or("a property before the first element")?
+                    .properties
+                    .push((
+                        name.to_string(),
+                        Property::List(scalar(length)?, scalar(item)?),
+                    ));
+            }
+            ["property", kind, name] => {
+                let scalar = Scalar::parse(kind).ok_or(format!("unknown type '{}'", kind))?;
+                elements
+                    .last_mut()
+                    .ok_or("a property before the first element")?
+                    .properties
+                    .push((name.to_string(), Property::Scalar(scalar)));
+            }
+            _ => {} // ply, comment, obj_info and empty lines
+        }
+    }
+    let format = format.ok_or("the header has no format")?;
+    Ok((format, elements, pos))
+}
+
+// the properties if the element has all three of them
+fn all([a, b, c]: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    Some([a?, b?, c?])
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    fn ply(format: &str, body: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            format!("ply\nformat {} 1.0\ncomment a quad\n{}", format, HEADER).into_bytes();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    // the same quad in binary, with the bytes of every value put in order by to_bytes
+    fn binary_body(to_bytes: fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (x, y) in [(0.0f32, 0.0f32), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+            for v in [x, y, 2.0] {
+                body.extend(to_bytes(&v.to_le_bytes()));
+            }
+            body.extend([255, 0, 0]);
+        }
+        body.push(4);
+        for i in 0..4i32 {
+            body.extend(to_bytes(&i.to_le_bytes()));
+        }
+        body
+    }
+
+    fn check_quad(data: &MeshData) {
+        assert_eq!(
+            data.positions,
+            vec![
+                [0.0, 0.0, 2.0],
+                [1.0, 0.0, 2.0],
+                [1.0, 1.0, 2.0],
+                [0.0, 1.0, 2.0]
+            ]
+        );
+        assert_eq!(data.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(data.colors, vec![[1.0, 0.0, 0.0]; 4]);
+        assert!(data.normals.is_empty());
+    }
+
+    #[test]
+    fn ascii() {
+        let body = b"0 0 2 255 0 0\n1 0 2 255 0 0\n1 1 2 255 0 0\n0 1 2 255 0 0\n4 0 1 2 3\n";
+        check_quad(&parse(&ply("ascii", body)).unwrap());
+    }
+
+    #[test]
+    fn binary_little_endian() {
+        let body = binary_body(|b| b.to_vec());
+        check_quad(&parse(&ply("binary_little_endian", &body)).unwrap());
+    }
+
+    #[test]
+    fn binary_big_endian() {
+        let body = binary_body(|b| b.iter().rev().copied().collect());
+        check_quad(&parse(&ply("binary_big_endian", &body)).unwrap());
+    }
+
+    #[test]
+    fn polygons_become_fans() {
+        let bytes = b"ply
+format ascii 1.0
+element vertex 5
+property float x
+property float y
+property float z
+element face 2
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+1 1 0
+0 1 0
+0 2 0
+5 0 1 2 3 4
+3 4 3 2
+";
+        let data = parse(bytes).unwrap();
+        assert_eq!(data.indices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4, 4, 3, 2]);
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        let body = b"0 0 2 255 0 0\n1 0 2 255 0 0\n1 1 2 255 0 0\n0 1 2 255 0 0\n3 0 1 4\n";
+        let error = parse(&ply("ascii", body)).unwrap_err();
+        assert!(error.contains("vertex 4"), "{}", error);
+    }
+
+    #[test]
+    fn cut_short() {
+        let body = binary_body(|b| b.to_vec());
+        assert!(parse(&ply("binary_little_endian", &body[..body.len() - 2])).is_err());
+    }
+}
@@ -1,83 +1,252 @@
+use crate::blue_noise;
 use crate::model;
-use glam::Mat3;
+use crate::punctual;
+use crate::sobol;
+use glam::{Mat3, Vec3};
+use std::fmt;
+use std::str::FromStr;
 use wgpu::util::DeviceExt;
 
+/*
+ * everything about a render that is decided up front
+ * and not by the scene or the size of the output
+ */
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub camera: CameraPose,
+    // radians from the bottom to the top of the image
+    pub vertical_fov: f32,
+    pub max_bounces: u32,
+    // bounces before russian roulette may end a path, max_bounces or more turns it off
+    pub roulette_depth: u32,
+    // the same seed gives the same image, different ones independent noise
+    pub seed: u32,
+    pub sampler: Sampler,
+    /*
+     * adaptive sampling, pixels stop taking samples once the standard error of their
+     * brightness over its square root is below this, None samples every pixel forever
+     */
+    pub error_threshold: Option<f32>,
+}
+
+/*
+ * where the random numbers of a sample come from
+ * sobol is a low discrepancy sequence, owen scrambled differently in every pixel,
+ * it gets to the same noise level with fewer samples
+ * blue noise shares one sobol sequence between all pixels and offsets it with a blue noise
+ * mask, the error is just as low but spread evenly, which looks best at a few samples
+ * random is independent pcg white noise, mostly to compare against
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Sampler {
+    #[default]
+    Sobol,
+    Random,
+    BlueNoise,
+}
+
+impl FromStr for Sampler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sobol" => Ok(Sampler::Sobol),
+            "random" => Ok(Sampler::Random),
+            "blue-noise" => Ok(Sampler::BlueNoise),
+            _ => Err(format!(
+                "unknown sampler '{}', expected sobol, random or blue-noise",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sampler::Sobol => write!(f, "sobol"),
+            Sampler::Random => write!(f, "random"),
+            Sampler::BlueNoise => write!(f, "blue-noise"),
+        }
+    }
+}
+
+/*
+ * where the camera is and where it looks
+ * yaw turns around the y axis and pitch tilts up and down, both in radians
+ * with both at 0 the camera looks along +z with +y up
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraPose {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl CameraPose {
+    pub fn rotation(&self) -> Mat3 {
+        Mat3::from_rotation_y(self.yaw) * Mat3::from_rotation_x(self.pitch)
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        self.rotation() * Vec3::Z
+    }
+
+    // the image is mirrored in x, so right on screen is -x in camera space
+    pub fn right(&self) -> Vec3 {
+        self.rotation() * Vec3::NEG_X
+    }
+}
+
+// laid out like the Camera struct in compute.wgsl, mat3x3f columns are padded to 16 bytes
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Camera {
+struct Camera {
     position: [f32; 3],
-    _padding: u32,
     yaw: f32,
     pitch: f32,
-    rot: [[f32; 3]; 3],
-    _padding2: [u32; 3],
+    _padding: [u32; 3],
+    rot: [[f32; 4]; 3],
     aspect: f32,
     focal_length: f32,
     znear: f32,
     zfar: f32,
-    _padding3: [u32; 2],
+}
+
+impl Camera {
+    fn new(pose: &CameraPose, vertical_fov: f32, width: u32, height: u32) -> Self {
+        let rot = pose.rotation();
+        Camera {
+            position: pose.position.to_array(),
+            yaw: pose.yaw,
+            pitch: pose.pitch,
+            _padding: [0; 3],
+            rot: [
+                rot.x_axis.extend(0.0).to_array(),
+                rot.y_axis.extend(0.0).to_array(),
+                rot.z_axis.extend(0.0).to_array(),
+            ],
+            aspect: width as f32 / height as f32,
+            // in pixels, the image plane is this far in front of the camera
+            focal_length: height as f32 / 2.0 / (vertical_fov / 2.0).tan(),
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
 }
 
 pub struct Pt {
     pt_buffer: wgpu::Buffer,
     pt_info_buffer: wgpu::Buffer,
+    convergence_buffer: wgpu::Buffer,
+    // where the count of pixels that are not done yet is copied to for reading it back
+    active_buffer: wgpu::Buffer,
     pt_bind_group_layout: wgpu::BindGroupLayout,
     pt_bind_group: wgpu::BindGroup,
     compute_pipeline: wgpu::ComputePipeline,
+    converge_pipeline: wgpu::ComputePipeline,
     samples_per_pixel: u32,
-    size: winit::dpi::PhysicalSize<u32>,
+    max_bounces: u32,
+    roulette_depth: u32,
+    seed: u32,
+    sampler: Sampler,
+    // how often reset was called, moves the blue noise around while the camera moves
+    restarts: u32,
+    // 0 without adaptive sampling
+    error_threshold: f32,
+    width: u32,
+    height: u32,
     model_bind_group: wgpu::BindGroup,
     camera_bind_group: wgpu::BindGroup,
+    punctual_bind_group: wgpu::BindGroup,
+    camera_buffer: wgpu::Buffer,
+    camera_pose: CameraPose,
+    vertical_fov: f32,
 }
 
 impl Pt {
-    pub fn new(device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &model::Scene,
+        width: u32,
+        height: u32,
+        config: &Config,
+    ) -> Result<Self, model::SceneError> {
         let model_bind_group_layout = model::bind_group_layout(device);
-        let model_bind_group =
-            model::load(device, &model_bind_group_layout, "res/cornell_box.obj").unwrap();
-
-        let camera = Camera {
-            position: [250.0, 250.0, -500.0],
-            _padding: 0,
-            yaw: 0.0,
-            pitch: 0.0,
-            rot: Mat3::IDENTITY.to_cols_array_2d(),
-            _padding2: [0, 0, 0],
-            aspect: size.width as f32 / size.height as f32,
-            focal_length: size.height as f32 / 2.0,
-            znear: 0.1,
-            zfar: 100.0,
-            _padding3: [0, 0],
-        };
+        let model_bind_group = scene.bind_group(device, queue, &model_bind_group_layout)?;
+        let punctual_bind_group_layout = punctual::bind_group_layout(device);
+        let punctual_bind_group = scene.punctual_bind_group(device, &punctual_bind_group_layout);
+
+        let camera_pose = config.camera;
+        let camera = Camera::new(&camera_pose, config.vertical_fov, width, height);
 
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera Buffer"),
             contents: bytemuck::cast_slice(&[camera]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // the sobol directions never change, so they live next to the camera
+        let sobol_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sobol Buffer"),
+            contents: bytemuck::cast_slice(&sobol::directions()),
             usage: wgpu::BufferUsages::UNIFORM,
         });
 
+        // making the mask takes a moment, the other samplers never read it so they get a single texel
+        let blue_noise_mask = match config.sampler {
+            Sampler::BlueNoise => blue_noise::generate(),
+            Sampler::Sobol | Sampler::Random => vec![0.0],
+        };
+        let blue_noise_view = blue_noise::create_texture(device, queue, &blue_noise_mask);
+
+        let uniform_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("camera_bind_group_layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    uniform_entry(0),
+                    uniform_entry(1),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                ],
             });
 
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("camera_bind_group"),
             layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: sobol_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&blue_noise_view),
+                },
+            ],
         });
 
         let samples_per_pixel = 0;
@@ -86,7 +255,25 @@ impl Pt {
          * We need to create a special texture buffer to draw our result to
          * since we cannot draw directly to the screen from a compute shader
          */
-        let (pt_buffer, pt_info_buffer) = create_pt_bufs(device, size, samples_per_pixel);
+        let pt_info = PtInfo {
+            width,
+            height,
+            samples_per_pixel,
+            max_bounces: config.max_bounces,
+            seed: config.seed,
+            sampler_type: config.sampler as u32,
+            restarts: 0,
+            error_threshold: config.error_threshold.unwrap_or(0.0),
+            roulette_depth: config.roulette_depth,
+            _padding: [0; 3],
+        };
+        let (pt_buffer, pt_info_buffer, convergence_buffer) = create_pt_bufs(device, pt_info);
+        let active_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Active Pixels Buffer"),
+            size: 4,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
 
         /*
          * The fragment and compute shaders will both access the same pt texture
@@ -95,42 +282,20 @@ impl Pt {
          * for both purposes by setting access: ReadWrite or something, havent tried yet
          */
 
-        let pt_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("pt_bind_group_layout"),
-                entries: &[
-                    // Pt
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    // PtInfo
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-            });
+        let pt_bind_group_layout = create_pt_bind_group_layout(device);
 
-        let pt_bind_group =
-            create_pt_bind_group(device, &pt_buffer, &pt_info_buffer, &pt_bind_group_layout);
+        let pt_bind_group = create_pt_bind_group(
+            device,
+            &pt_buffer,
+            &pt_info_buffer,
+            &convergence_buffer,
+            &pt_bind_group_layout,
+        );
         let compute_shader = device.create_shader_module(wgpu::include_wgsl!("compute.wgsl"));
 
         /*
-         * we will have one bind group for the texture we are drawing to
-         * and one bind group for the model
+         * we will have one bind group for the texture we are drawing to,
+         * one for the model, one for the camera and sampler and one for the punctual lights
          */
         let compute_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -139,6 +304,7 @@ impl Pt {
                     &pt_bind_group_layout,
                     &model_bind_group_layout,
                     &camera_bind_group_layout,
+                    &punctual_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
@@ -152,17 +318,34 @@ impl Pt {
             cache: None,
         });
 
-        Pt {
+        let converge_pipeline =
+            create_converge_pipeline(device, &compute_shader, &pt_bind_group_layout);
+
+        Ok(Pt {
             pt_buffer,
             pt_info_buffer,
+            convergence_buffer,
+            active_buffer,
             pt_bind_group_layout,
             pt_bind_group,
             compute_pipeline,
+            converge_pipeline,
             samples_per_pixel,
-            size,
+            max_bounces: config.max_bounces,
+            roulette_depth: config.roulette_depth,
+            seed: config.seed,
+            sampler: config.sampler,
+            restarts: 0,
+            error_threshold: config.error_threshold.unwrap_or(0.0),
+            width,
+            height,
             model_bind_group,
             camera_bind_group,
-        }
+            punctual_bind_group,
+            camera_buffer,
+            camera_pose,
+            vertical_fov: config.vertical_fov,
+        })
     }
 
     pub fn encode_compute(&self, encoder: &mut wgpu::CommandEncoder) {
@@ -174,8 +357,27 @@ impl Pt {
         compute_pass.set_bind_group(0, &self.pt_bind_group, &[]);
         compute_pass.set_bind_group(1, &self.model_bind_group, &[]);
         compute_pass.set_bind_group(2, &self.camera_bind_group, &[]);
+        compute_pass.set_bind_group(3, &self.punctual_bind_group, &[]);
+
+        compute_pass.dispatch_workgroups(self.width, self.height, 1);
+        drop(compute_pass);
 
-        compute_pass.dispatch_workgroups(self.size.width, self.size.height, 1);
+        /*
+         * with adaptive sampling every sample is followed by marking the pixels that are done,
+         * counting the others from 0 and copying that count out for converged
+         * so the count always includes the sample just taken
+         */
+        if self.error_threshold > 0.0 {
+            encode_converge(
+                encoder,
+                &self.converge_pipeline,
+                &self.pt_bind_group,
+                &self.convergence_buffer,
+                self.width,
+                self.height,
+            );
+            encoder.copy_buffer_to_buffer(&self.convergence_buffer, 0, &self.active_buffer, 0, 4);
+        }
     }
 
     pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
@@ -186,27 +388,143 @@ impl Pt {
         &self.pt_bind_group
     }
 
-    pub fn resize(&mut self, device: &wgpu::Device, new_size: winit::dpi::PhysicalSize<u32>) {
-        self.size = new_size;
-        (self.pt_buffer, self.pt_info_buffer) =
-            create_pt_bufs(device, new_size, self.samples_per_pixel);
+    pub fn resize(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.write_camera(queue);
+        (self.pt_buffer, self.pt_info_buffer, self.convergence_buffer) =
+            create_pt_bufs(device, self.pt_info());
         self.pt_bind_group = create_pt_bind_group(
             device,
             &self.pt_buffer,
             &self.pt_info_buffer,
+            &self.convergence_buffer,
             &self.pt_bind_group_layout,
         );
     }
 
+    pub fn camera_pose(&self) -> CameraPose {
+        self.camera_pose
+    }
+
+    /*
+     * move the camera, the accumulated samples no longer match what it sees
+     * so the caller has to reset if this returns true
+     */
+    pub fn set_camera_pose(&mut self, queue: &wgpu::Queue, pose: CameraPose) -> bool {
+        if pose == self.camera_pose {
+            return false;
+        }
+        self.camera_pose = pose;
+        self.write_camera(queue);
+        true
+    }
+
+    fn write_camera(&self, queue: &wgpu::Queue) {
+        let camera = Camera::new(
+            &self.camera_pose,
+            self.vertical_fov,
+            self.width,
+            self.height,
+        );
+        queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[camera]));
+    }
+
     pub fn next_frame(&mut self, queue: &wgpu::Queue) {
         self.samples_per_pixel += 1;
-        let pt_info = PtInfo {
-            width: self.size.width,
-            height: self.size.height,
+        queue.write_buffer(
+            &self.pt_info_buffer,
+            0,
+            bytemuck::cast_slice(&[self.pt_info()]),
+        );
+    }
+
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // throw away everything accumulated so far and start over from 0 samples
+    pub fn reset(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        self.samples_per_pixel = 0;
+        self.restarts = self.restarts.wrapping_add(1);
+        encoder.clear_buffer(&self.pt_buffer, 0, None);
+        encoder.clear_buffer(&self.convergence_buffer, 0, None);
+    }
+
+    /*
+     * whether the last sample found every pixel below the error threshold,
+     * after that no pixel takes samples anymore, always false without adaptive sampling
+     * blocks until the gpu has finished all submitted work
+     */
+    pub fn converged(&self, device: &wgpu::Device) -> bool {
+        if self.error_threshold <= 0.0 || self.samples_per_pixel == 0 {
+            return false;
+        }
+        let slice = self.active_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map active pixels buffer")
+        });
+        device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .expect("failed to wait for gpu");
+        let active = bytemuck::cast_slice::<u8, u32>(&slice.get_mapped_range())[0];
+        self.active_buffer.unmap();
+        active == 0
+    }
+
+    fn pt_info(&self) -> PtInfo {
+        PtInfo {
+            width: self.width,
+            height: self.height,
             samples_per_pixel: self.samples_per_pixel,
-            _padding: 0,
-        };
-        queue.write_buffer(&self.pt_info_buffer, 0, bytemuck::cast_slice(&[pt_info]));
+            max_bounces: self.max_bounces,
+            seed: self.seed,
+            sampler_type: self.sampler as u32,
+            restarts: self.restarts,
+            error_threshold: self.error_threshold,
+            roulette_depth: self.roulette_depth,
+            _padding: [0; 3],
+        }
+    }
+
+    /*
+     * copy the accumulated pt buffer back to the cpu
+     * each pixel is the sum of all samples so far, with the sample count in alpha
+     * blocks until the gpu has finished all submitted work
+     */
+    pub fn read_back(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<[f32; 4]> {
+        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("pt_staging_buffer"),
+            size: self.pt_buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Read Back Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.pt_buffer, 0, &staging_buffer, 0, None);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = staging_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map pt staging buffer")
+        });
+        device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .expect("failed to wait for gpu");
+
+        let pixels = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+        pixels
     }
 }
 
@@ -216,43 +534,125 @@ struct PtInfo {
     width: u32,
     height: u32,
     samples_per_pixel: u32,
-    _padding: u32,
+    max_bounces: u32,
+    seed: u32,
+    // a Sampler, sobol is 0, random 1 and blue noise 2
+    sampler_type: u32,
+    restarts: u32,
+    error_threshold: f32,
+    roulette_depth: u32,
+    _padding: [u32; 3],
+}
+
+// an rgba32float sum per pixel, in u64 since big images overflow a u32
+fn pt_buffer_size(width: u32, height: u32) -> u64 {
+    16 * width as u64 * height as u64
+}
+
+// the active count padded to 8 bytes, then a vec2f per pixel, see Convergence in compute.wgsl
+fn convergence_buffer_size(width: u32, height: u32) -> u64 {
+    8 + 8 * width as u64 * height as u64
+}
+
+/*
+ * whether the device can render an image this big, one workgroup per pixel
+ * has to fit in a dispatch and the pixel sums in a storage buffer
+ */
+pub(crate) fn check_size(limits: &wgpu::Limits, width: u32, height: u32) -> Result<(), String> {
+    let max_dispatch = limits.max_compute_workgroups_per_dimension;
+    if width > max_dispatch || height > max_dispatch {
+        return Err(format!(
+            "{}x{} is too big, the device renders at most {} pixels in each direction",
+            width, height, max_dispatch
+        ));
+    }
+    let limit = limits
+        .max_buffer_size
+        .min(limits.max_storage_buffer_binding_size as u64);
+    let size = pt_buffer_size(width, height).max(convergence_buffer_size(width, height));
+    if size > limit {
+        return Err(format!(
+            "{}x{} is too big, its {} bytes of pixels are more than the {} the device allows",
+            width, height, size, limit
+        ));
+    }
+    Ok(())
 }
 
 fn create_pt_bufs(
     device: &wgpu::Device,
-    size: winit::dpi::PhysicalSize<u32>,
-    samples_per_pixel: u32,
-) -> (wgpu::Buffer, wgpu::Buffer) {
+    pt_info: PtInfo,
+) -> (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer) {
     let pt_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("pt_buffer"),
-        size: (wgpu::TextureFormat::Rgba32Float
-            .block_copy_size(None)
-            .unwrap()
-            * size.width
-            * size.height)
-            .into(),
-        usage: wgpu::BufferUsages::STORAGE,
+        size: pt_buffer_size(pt_info.width, pt_info.height),
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
         mapped_at_creation: false,
     });
-    let pt_info = PtInfo {
-        width: size.width,
-        height: size.height,
-        samples_per_pixel,
-        _padding: 0,
-    };
     let pt_info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Path Trace Info Buffer"),
         contents: bytemuck::cast_slice(&[pt_info]),
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
-    (pt_buffer, pt_info_buffer)
+    let convergence_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Convergence Buffer"),
+        size: convergence_buffer_size(pt_info.width, pt_info.height),
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    (pt_buffer, pt_info_buffer, convergence_buffer)
+}
+
+fn create_pt_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("pt_bind_group_layout"),
+        entries: &[
+            // Pt
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // PtInfo
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // Convergence, only the path tracer needs it
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
 }
 
 fn create_pt_bind_group(
     device: &wgpu::Device,
     pt_buffer: &wgpu::Buffer,
     pt_info_buffer: &wgpu::Buffer,
+    convergence_buffer: &wgpu::Buffer,
     pt_bg_layout: &wgpu::BindGroupLayout,
 ) -> wgpu::BindGroup {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -269,6 +669,191 @@ fn create_pt_bind_group(
                 binding: 1,
                 resource: pt_info_buffer.as_entire_binding(),
             },
+            // Convergence
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: convergence_buffer.as_entire_binding(),
+            },
         ],
     })
 }
+
+// deciding which pixels are done only looks at what is accumulated so far
+fn create_converge_pipeline(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    pt_bg_layout: &wgpu::BindGroupLayout,
+) -> wgpu::ComputePipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Converge Pipeline Layout"),
+        bind_group_layouts: &[pt_bg_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Converge Pipeline"),
+        layout: Some(&layout),
+        module: shader,
+        entry_point: Some("converge"),
+        compilation_options: Default::default(),
+        cache: None,
+    })
+}
+
+// marks the pixels that are done and counts the others into the first 4 bytes of convergence
+fn encode_converge(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::ComputePipeline,
+    pt_bind_group: &wgpu::BindGroup,
+    convergence_buffer: &wgpu::Buffer,
+    width: u32,
+    height: u32,
+) {
+    encoder.clear_buffer(convergence_buffer, 0, Some(4));
+    let mut converge_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some("Converge Pass"),
+        timestamp_writes: None,
+    });
+    converge_pass.set_pipeline(pipeline);
+    converge_pass.set_bind_group(0, pt_bind_group, &[]);
+    converge_pass.dispatch_workgroups(width, height, 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pollster::FutureExt;
+
+    // any adapter will do, a software one included, but without one the test fails
+    fn device() -> (wgpu::Device, wgpu::Queue) {
+        let instance = wgpu::Instance::default();
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                force_fallback_adapter: false,
+                ..Default::default()
+            })
+            .block_on()
+            .or_else(|_| {
+                instance
+                    .request_adapter(&wgpu::RequestAdapterOptions {
+                        force_fallback_adapter: true,
+                        ..Default::default()
+                    })
+                    .block_on()
+            })
+            .expect("no adapter, not even a software one like lavapipe");
+        adapter
+            .request_device(&Default::default())
+            .block_on()
+            .unwrap()
+    }
+
+    /*
+     * runs the converge pass over one row of pixels given as (samples, luminances of half of them,
+     * luminances of the other half) and returns how many are left and which ones are done
+     */
+    fn converge(threshold: f32, pixels: &[(u32, f32, f32)]) -> (u32, Vec<bool>) {
+        let (device, queue) = device();
+        let (width, height) = (pixels.len() as u32, 1);
+        let (pt_buffer, pt_info_buffer, convergence_buffer) = create_pt_bufs(
+            &device,
+            PtInfo {
+                width,
+                height,
+                samples_per_pixel: 0,
+                max_bounces: 0,
+                seed: 0,
+                sampler_type: 0,
+                restarts: 0,
+                error_threshold: threshold,
+                roulette_depth: 0,
+                _padding: [0; 3],
+            },
+        );
+        let sums = pixels
+            .iter()
+            .flat_map(|&(n, a, b)| {
+                let sum = (a + b) * n as f32 / 2.0;
+                [sum, sum, sum, n as f32]
+            })
+            .collect::<Vec<f32>>();
+        let mut squares = vec![0.0f32; 2];
+        for &(n, a, b) in pixels {
+            squares.extend([(a * a + b * b) * n as f32 / 2.0, 0.0]);
+        }
+        queue.write_buffer(&pt_buffer, 0, bytemuck::cast_slice(&sums));
+        queue.write_buffer(&convergence_buffer, 0, bytemuck::cast_slice(&squares));
+
+        let layout = create_pt_bind_group_layout(&device);
+        let bind_group = create_pt_bind_group(
+            &device,
+            &pt_buffer,
+            &pt_info_buffer,
+            &convergence_buffer,
+            &layout,
+        );
+        let shader = device.create_shader_module(wgpu::include_wgsl!("compute.wgsl"));
+        let pipeline = create_converge_pipeline(&device, &shader, &layout);
+        let read_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: convergence_buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        encode_converge(
+            &mut encoder,
+            &pipeline,
+            &bind_group,
+            &convergence_buffer,
+            width,
+            height,
+        );
+        encoder.copy_buffer_to_buffer(&convergence_buffer, 0, &read_buffer, 0, None);
+        queue.submit([encoder.finish()]);
+
+        let slice = read_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        let data = slice.get_mapped_range();
+        let unconverged = bytemuck::cast_slice::<u8, u32>(&data[..4])[0];
+        let done = bytemuck::cast_slice::<u8, f32>(&data[8..])
+            .chunks(2)
+            .map(|pixel| pixel[1] != 0.0)
+            .collect();
+        (unconverged, done)
+    }
+
+    #[test]
+    fn noiseless_pixels_wait_for_the_minimum() {
+        let (unconverged, done) = converge(0.05, &[(15, 0.5, 0.5), (16, 0.5, 0.5)]);
+        // the second has no noise but its neighbour is still below MIN_ADAPTIVE_SAMPLES
+        assert_eq!(unconverged, 2);
+        assert_eq!(done, [false, false]);
+
+        let (unconverged, done) = converge(0.05, &[(16, 0.5, 0.5), (16, 0.5, 0.5)]);
+        assert_eq!(unconverged, 0);
+        assert_eq!(done, [true, true]);
+    }
+
+    #[test]
+    fn noise_is_compared_to_the_threshold() {
+        /*
+         * half 0 and half 1 over 16 samples has a mean of 0.5 and a variance of 4/15,
+         * so an error of sqrt(4/15 / 16) / sqrt(0.5) = 0.183
+         */
+        let pixels = [
+            (16, 0.0, 1.0),
+            (16, 0.5, 0.5),
+            (16, 0.5, 0.5),
+            (16, 0.5, 0.5),
+        ];
+        let (unconverged, done) = converge(0.18, &pixels);
+        // the noisy pixel keeps its neighbour going too
+        assert_eq!(unconverged, 2);
+        assert_eq!(done, [false, false, true, true]);
+
+        let (unconverged, done) = converge(0.19, &pixels);
+        assert_eq!(unconverged, 0);
+        assert_eq!(done, [true, true, true, true]);
+    }
+}
@@ -0,0 +1,314 @@
+use glam::Vec3;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ * lights without any geometry, they are only found by light sampling and never hit by rays
+ * intensity is radiant intensity (radiance times area) for point and spot lights
+ * and irradiance perpendicular to the light for directional ones
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PunctualLight {
+    Point {
+        position: Vec3,
+        intensity: Vec3,
+    },
+    // full intensity inside falloff_start, fading out to nothing at cone_angle, both in radians
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        cone_angle: f32,
+        falloff_start: f32,
+    },
+    // like the sun, direction is where the light travels, a radius of 0 gives hard shadows
+    Directional {
+        direction: Vec3,
+        irradiance: Vec3,
+        angular_radius: f32,
+    },
+}
+
+const POINT: u32 = 0;
+const SPOT: u32 = 1;
+const DIRECTIONAL: u32 = 2;
+
+// laid out like PunctualLight in compute.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct GpuPunctualLight {
+    position: [f32; 3],
+    kind: u32,
+    direction: [f32; 3],
+    cos_outer: f32,
+    intensity: [f32; 3],
+    cos_inner: f32,
+}
+
+impl From<&PunctualLight> for GpuPunctualLight {
+    fn from(light: &PunctualLight) -> Self {
+        match *light {
+            PunctualLight::Point {
+                position,
+                intensity,
+            } => GpuPunctualLight {
+                position: position.to_array(),
+                kind: POINT,
+                direction: [0.0; 3],
+                cos_outer: -1.0,
+                intensity: intensity.to_array(),
+                cos_inner: -1.0,
+            },
+            PunctualLight::Spot {
+                position,
+                direction,
+                intensity,
+                cone_angle,
+                falloff_start,
+            } => GpuPunctualLight {
+                position: position.to_array(),
+                kind: SPOT,
+                direction: direction.normalize().to_array(),
+                cos_outer: cone_angle.cos(),
+                intensity: intensity.to_array(),
+                cos_inner: falloff_start.min(cone_angle).cos(),
+            },
+            PunctualLight::Directional {
+                direction,
+                irradiance,
+                angular_radius,
+            } => GpuPunctualLight {
+                position: [0.0; 3],
+                kind: DIRECTIONAL,
+                direction: direction.normalize().to_array(),
+                cos_outer: angular_radius.cos(),
+                intensity: irradiance.to_array(),
+                cos_inner: 1.0,
+            },
+        }
+    }
+}
+
+// everything that can go wrong when loading a lights file
+#[derive(Debug)]
+pub enum PunctualLightError {
+    // the file could not be read
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // a line we do not understand
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for PunctualLightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PunctualLightError::Io { path, source } => {
+                write!(
+                    f,
+                    "{}: failed to read lights file: {}",
+                    path.display(),
+                    source
+                )
+            }
+            PunctualLightError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for PunctualLightError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PunctualLightError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * read a lights file, one light per line in the spirit of obj files, angles in degrees
+ * point x y z  r g b
+ * spot x y z  dx dy dz  r g b  cone_angle falloff_start
+ * directional dx dy dz  r g b  angular_radius
+ * empty lines and lines starting with # are ignored
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<PunctualLight>, PunctualLightError> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|source| PunctualLightError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(path, &text)
+}
+
+// the path is only for the errors
+fn parse(path: &Path, text: &str) -> Result<Vec<PunctualLight>, PunctualLightError> {
+    let mut lights = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse_err = |message: String| PunctualLightError::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message,
+        };
+        let mut words = line.split_whitespace();
+        let kind = words.next().unwrap_or_default();
+        let numbers = words
+            .map(|w| {
+                w.parse::<f32>()
+                    .map_err(|_| parse_err(format!("'{}' is not a number", w)))
+            })
+            .collect::<Result<Vec<f32>, PunctualLightError>>()?;
+        let expected = match kind {
+            "point" => 6,
+            "spot" => 11,
+            "directional" => 7,
+            _ => return Err(parse_err(format!("unknown light type '{}'", kind))),
+        };
+        if numbers.len() != expected {
+            return Err(parse_err(format!(
+                "{} lights take {} numbers, got {}",
+                kind,
+                expected,
+                numbers.len()
+            )));
+        }
+        let v = |i: usize| Vec3::new(numbers[i], numbers[i + 1], numbers[i + 2]);
+        lights.push(match kind {
+            "point" => PunctualLight::Point {
+                position: v(0),
+                intensity: v(3),
+            },
+            "spot" => PunctualLight::Spot {
+                position: v(0),
+                direction: v(3),
+                intensity: v(6),
+                cone_angle: numbers[9].to_radians(),
+                falloff_start: numbers[10].to_radians(),
+            },
+            _ => PunctualLight::Directional {
+                direction: v(0),
+                irradiance: v(3),
+                angular_radius: numbers[6].to_radians(),
+            },
+        });
+        if let PunctualLight::Spot { direction, .. } | PunctualLight::Directional { direction, .. } =
+            lights[lights.len() - 1]
+            && direction.length_squared() == 0.0
+        {
+            return Err(parse_err("direction can not be 0".to_string()));
+        }
+    }
+    Ok(lights)
+}
+
+pub(crate) fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("punctual_bind_group_layout"),
+        entries: &[
+            // PunctualLights
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(text: &str) -> Result<Vec<PunctualLight>, PunctualLightError> {
+        parse(Path::new("test.lights"), text)
+    }
+
+    // the line and message of a parse error
+    fn error(text: &str) -> (usize, String) {
+        match parse_str(text) {
+            Err(PunctualLightError::Parse { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_kind_of_light() {
+        let lights = parse_str(
+            "point 1 2 3  10 20 30\n\
+             spot 0 5 0  0 -1 0  100 100 100  45 30\n\
+             directional 0 -1 1  3 3 3  0.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lights[0],
+            PunctualLight::Point {
+                position: Vec3::new(1.0, 2.0, 3.0),
+                intensity: Vec3::new(10.0, 20.0, 30.0),
+            }
+        );
+        assert_eq!(
+            lights[1],
+            PunctualLight::Spot {
+                position: Vec3::new(0.0, 5.0, 0.0),
+                direction: Vec3::new(0.0, -1.0, 0.0),
+                intensity: Vec3::splat(100.0),
+                cone_angle: 45f32.to_radians(),
+                falloff_start: 30f32.to_radians(),
+            }
+        );
+        assert_eq!(
+            lights[2],
+            PunctualLight::Directional {
+                direction: Vec3::new(0.0, -1.0, 1.0),
+                irradiance: Vec3::splat(3.0),
+                angular_radius: 0.5f32.to_radians(),
+            }
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let lights = parse_str("# a lamp\n\n   \n  # indented\npoint 0 0 0 1 1 1\n\n").unwrap();
+        assert_eq!(lights.len(), 1);
+        assert_eq!(parse_str("").unwrap(), []);
+    }
+
+    #[test]
+    fn unknown_light() {
+        let (line, message) = error("point 0 0 0 1 1 1\n\narea 0 0 0 1 1 1\n");
+        assert_eq!(line, 3);
+        assert!(message.contains("unknown light type 'area'"), "{}", message);
+    }
+
+    #[test]
+    fn wrong_numbers() {
+        let (line, message) = error("spot 0 5 0  0 -1 0  100 100 100  45\n");
+        assert_eq!(line, 1);
+        assert!(message.contains("take 11 numbers, got 10"), "{}", message);
+
+        let (_, message) = error("point 0 0 zero 1 1 1\n");
+        assert!(message.contains("'zero' is not a number"), "{}", message);
+
+        let (_, message) = error("directional 0 0 0  1 1 1  0\n");
+        assert!(message.contains("direction can not be 0"), "{}", message);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::model::{Scene, SceneError};
+use crate::pt;
+
+// the scene bind group has this many storage buffers, more than the wgpu default of 8
+const STORAGE_BUFFERS: u32 = 16;
+
+/*
+ * the public face of the path tracer
+ * the host owns the device and queue, we only keep handles to them
+ * every call to accumulate adds samples on top of what is already there
+ * until reset is called (or the size changes)
+ */
+pub struct Renderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pt: pt::Pt,
+}
+
+impl Renderer {
+    /*
+     * the scene needs more storage buffers than the defaults of wgpu allow,
+     * and big scans or environment maps bigger buffers, so those go up to what the adapter can do
+     * pass these as required_limits when requesting the device from that adapter
+     * fails if the adapter can't bind all of the storage buffers
+     */
+    pub fn required_limits(adapter: &wgpu::Adapter) -> Result<wgpu::Limits, SceneError> {
+        let supported = adapter.limits();
+        if supported.max_storage_buffers_per_shader_stage < STORAGE_BUFFERS {
+            return Err(SceneError::TooFewStorageBuffers {
+                needed: STORAGE_BUFFERS,
+                limit: supported.max_storage_buffers_per_shader_stage,
+            });
+        }
+        Ok(wgpu::Limits {
+            max_storage_buffers_per_shader_stage: STORAGE_BUFFERS,
+            max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size,
+            max_buffer_size: supported.max_buffer_size,
+            ..Default::default()
+        })
+    }
+
+    // fails if the scene needs bigger buffers than the device allows
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+        config: &pt::Config,
+    ) -> Result<Self, SceneError> {
+        Ok(Renderer {
+            device: device.clone(),
+            queue: queue.clone(),
+            pt: pt::Pt::new(device, queue, scene, width, height, config)?,
+        })
+    }
+
+    // trace `samples` more samples for every pixel and submit them right away
+    pub fn accumulate(&mut self, samples: u32) {
+        for _ in 0..samples {
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Accumulate Encoder"),
+                });
+            self.encode_sample(&mut encoder);
+            self.queue.submit(std::iter::once(encoder.finish()));
+        }
+    }
+
+    /*
+     * record one more sample per pixel into an encoder the host submits itself,
+     * e.g. before drawing the result to a window in the same submission
+     * the sample info is written through the queue, so only encode one sample per submit
+     */
+    pub fn encode_sample(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        self.pt.next_frame(&self.queue);
+        self.pt.encode_compute(encoder);
+    }
+
+    // throw away all accumulated samples
+    pub fn reset(&mut self) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Reset Encoder"),
+            });
+        self.pt.reset(&mut encoder);
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /*
+     * whether the device can render an image of this size,
+     * new and resize panic in wgpu if it can't, so check user given sizes first
+     */
+    pub fn check_size(device: &wgpu::Device, width: u32, height: u32) -> Result<(), String> {
+        pt::check_size(&device.limits(), width, height)
+    }
+
+    // resizing reallocates the image, so this also throws away all samples
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.pt.resize(&self.device, &self.queue, width, height);
+        self.reset();
+    }
+
+    pub fn camera(&self) -> pt::CameraPose {
+        self.pt.camera_pose()
+    }
+
+    // moving the camera starts the accumulation over
+    pub fn set_camera(&mut self, pose: pt::CameraPose) {
+        if self.pt.set_camera_pose(&self.queue, pose) {
+            self.reset();
+        }
+    }
+
+    // with adaptive sampling this is the most any pixel got, converged ones stop earlier
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.pt.samples_per_pixel()
+    }
+
+    /*
+     * whether every pixel is below the error threshold of the config, so more samples
+     * would not change the image anymore, always false without adaptive sampling
+     * blocks until the gpu is done
+     */
+    pub fn converged(&self) -> bool {
+        self.pt.converged(&self.device)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.pt.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.pt.height()
+    }
+
+    /*
+     * the image so far as linear rgba, row by row from the top left
+     * averaged over the samples, so it is HDR and not clamped to [0, 1]
+     * blocks until the gpu is done
+     */
+    pub fn read_back(&self) -> Vec<[f32; 4]> {
+        self.pt
+            .read_back(&self.device, &self.queue)
+            .into_iter()
+            .map(|p| {
+                // same as fs_main in draw.wgsl, the alpha channel holds the number of samples
+                let a = if p[3] > 0.0 { p[3] } else { 1.0 };
+                [p[0] / a, p[1] / a, p[2] / a, 1.0]
+            })
+            .collect()
+    }
+
+    /*
+     * the accumulated image is exposed to other pipelines through this bind group
+     * binding 0 is the storage buffer of rgba sums (alpha is the sample count)
+     * binding 1 is the PtInfo uniform with the width and height
+     * binding 2 is the per pixel convergence state, only visible to compute shaders
+     * see draw.wgsl for how to use it
+     */
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        self.pt.bind_group_layout()
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        self.pt.bind_group()
+    }
+}
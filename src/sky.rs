@@ -0,0 +1,261 @@
+use crate::punctual::PunctualLight;
+use glam::{DVec3, Vec3};
+use std::f64::consts::PI;
+
+/*
+ * an analytic daylight sky for outdoor scenes, the preetham model
+ * https://courses.cs.duke.edu/cps124/fall01/resources/p91-preetham.pdf
+ * the sky is evaluated in the shader for rays that leave the scene,
+ * the sun is a small directional light so light sampling finds it
+ * below the horizon there is an endless flat ground lit by the sky and the sun
+ * radiance is in kcd/m^2 times intensity
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sky {
+    // radians above the horizon, the model only works for a sun above it
+    pub sun_elevation: f32,
+    // radians around the up axis, 0 is towards +z and pi/2 towards +x
+    pub sun_azimuth: f32,
+    // haziness, 2 is a very clear sky and 10 a hazy one, the model is only fitted for that range
+    pub turbidity: f32,
+    pub ground_albedo: [f32; 3],
+    // the default makes a white surface facing the midday sun about 1
+    pub intensity: f32,
+}
+
+// the sun seen from earth
+const SUN_ANGULAR_RADIUS: f64 = 0.2667 * PI / 180.0;
+// illuminance of the sun outside the atmosphere in klux
+const SUN_ILLUMINANCE: f64 = 128.0;
+// wavelengths in micrometers we take for red, green and blue
+const WAVELENGTHS: [f64; 3] = [0.65, 0.55, 0.45];
+
+// laid out like Sky in compute.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct GpuSky {
+    // perez coefficients for luminance and the two chromaticities
+    a: [f32; 3],
+    // 0 if there is no sky
+    enabled: u32,
+    b: [f32; 3],
+    cos_sun: f32,
+    c: [f32; 3],
+    _padding0: u32,
+    d: [f32; 3],
+    _padding1: u32,
+    e: [f32; 3],
+    _padding2: u32,
+    // zenith luminance and chromaticities, already divided by the perez function at the zenith
+    zenith: [f32; 3],
+    _padding3: u32,
+    sun_direction: [f32; 3],
+    _padding4: u32,
+    sun_radiance: [f32; 3],
+    _padding5: u32,
+    ground_radiance: [f32; 3],
+    _padding6: u32,
+}
+
+// the distribution of one of luminance, x and y over the sky
+#[derive(Copy, Clone)]
+struct Perez([f64; 5]);
+
+impl Perez {
+    fn new(turbidity: f64, t: [f64; 5], c: [f64; 5]) -> Perez {
+        Perez(std::array::from_fn(|i| t[i] * turbidity + c[i]))
+    }
+
+    // theta is the angle from the zenith, gamma the angle from the sun
+    fn eval(&self, cos_theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = self.0;
+        let cos_gamma = gamma.cos();
+        (1.0 + a * (b / cos_theta.max(0.001)).exp())
+            * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky {
+            sun_elevation: 45f32.to_radians(),
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            ground_albedo: [0.3; 3],
+            intensity: 0.025,
+        }
+    }
+}
+
+impl Sky {
+    // points from the ground to the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        let (sin_el, cos_el) = self.sun_elevation.sin_cos();
+        let (sin_az, cos_az) = self.sun_azimuth.sin_cos();
+        Vec3::new(sin_az * cos_el, sin_el, cos_az * cos_el)
+    }
+
+    fn sun_direction_f64(&self) -> DVec3 {
+        let mut dir = self.sun_direction().as_dvec3();
+        // keep the model in the range it was fitted for
+        dir.y = dir.y.max(0.0);
+        dir.normalize_or(DVec3::Y)
+    }
+
+    fn turbidity_f64(&self) -> f64 {
+        (self.turbidity as f64).clamp(2.0, 10.0)
+    }
+
+    fn perez(&self) -> [Perez; 3] {
+        let t = self.turbidity_f64();
+        [
+            Perez::new(
+                t,
+                [0.1787, -0.3554, -0.0227, 0.1206, -0.0670],
+                [-1.4630, 0.4275, 5.3251, -2.5771, 0.3703],
+            ),
+            Perez::new(
+                t,
+                [-0.0193, -0.0665, -0.0004, -0.0641, -0.0033],
+                [-0.2592, 0.0008, 0.2125, -0.8989, 0.0452],
+            ),
+            Perez::new(
+                t,
+                [-0.0167, -0.0950, -0.0079, -0.0441, -0.0109],
+                [-0.2608, 0.0092, 0.2102, -1.6537, 0.0529],
+            ),
+        ]
+    }
+
+    // luminance in kcd/m^2 and the x and y chromaticity straight up
+    fn zenith(&self) -> [f64; 3] {
+        let t = self.turbidity_f64();
+        let theta = self.sun_direction_f64().y.acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let poly = |c: [f64; 4]| c[0] * theta.powi(3) + c[1] * theta.powi(2) + c[2] * theta + c[3];
+        let x = t * t * poly([0.00166, -0.00375, 0.00209, 0.0])
+            + t * poly([-0.02903, 0.06377, -0.03202, 0.00394])
+            + poly([0.11693, -0.21196, 0.06052, 0.25886]);
+        let y = t * t * poly([0.00275, -0.00610, 0.00317, 0.0])
+            + t * poly([-0.04214, 0.08970, -0.04153, 0.00516])
+            + poly([0.15346, -0.26756, 0.06670, 0.26688]);
+        [luminance, x, y]
+    }
+
+    // linear rgb radiance of the sky in a direction above the horizon, without the sun
+    fn radiance(&self, perez: &[Perez; 3], zenith: [f64; 3], dir: DVec3) -> DVec3 {
+        let sun = self.sun_direction_f64();
+        let gamma = dir.dot(sun).clamp(-1.0, 1.0).acos();
+        let theta_sun = sun.y.acos();
+        let xyy: [f64; 3] = std::array::from_fn(|i| {
+            zenith[i] * perez[i].eval(dir.y, gamma) / perez[i].eval(1.0, theta_sun)
+        });
+        xyy_to_rgb(xyy) * self.intensity as f64
+    }
+
+    /*
+     * irradiance of the sun on a surface facing it, per color channel
+     * what makes it through the atmosphere, with rayleigh and aerosol scattering
+     * from the appendix of the paper, ozone and water vapour are left out
+     */
+    fn sun_irradiance(&self) -> DVec3 {
+        let sun = self.sun_direction_f64();
+        let theta_deg = sun.y.acos().to_degrees();
+        // how much air the light goes through compared to straight down
+        let air_mass = 1.0 / (sun.y + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608 * self.turbidity_f64() - 0.04586;
+        let transmittance = WAVELENGTHS.map(|l| {
+            let rayleigh = (-air_mass * 0.008735 * l.powf(-4.08)).exp();
+            let aerosol = (-air_mass * beta * l.powf(-1.3)).exp();
+            rayleigh * aerosol
+        });
+        DVec3::from_array(transmittance) * SUN_ILLUMINANCE * self.intensity as f64
+    }
+
+    pub(crate) fn gpu(&self) -> GpuSky {
+        let perez = self.perez();
+        let mut zenith = self.zenith();
+        let sun = self.sun_direction_f64();
+        let theta_sun = sun.y.acos();
+        for (z, p) in zenith.iter_mut().zip(&perez) {
+            *z /= p.eval(1.0, theta_sun);
+        }
+        zenith[0] *= self.intensity as f64;
+        let coefficient = |i: usize| perez.map(|p| p.0[i] as f32);
+
+        // same radiance as a directional light of that size, see punctual_lighting in compute.wgsl
+        let sin2_r = SUN_ANGULAR_RADIUS.sin().powi(2);
+        let sun_radiance = match self.sun() {
+            Some(_) => self.sun_irradiance() / (PI * sin2_r),
+            None => DVec3::ZERO,
+        };
+
+        // sky and sun on a flat diffuse ground
+        let irradiance = self.sky_irradiance() + self.sun_irradiance() * sun.y;
+        let ground_radiance =
+            DVec3::from_array(self.ground_albedo.map(|a| a as f64)) * irradiance / PI;
+
+        GpuSky {
+            a: coefficient(0),
+            enabled: 1,
+            b: coefficient(1),
+            cos_sun: SUN_ANGULAR_RADIUS.cos() as f32,
+            c: coefficient(2),
+            _padding0: 0,
+            d: coefficient(3),
+            _padding1: 0,
+            e: coefficient(4),
+            _padding2: 0,
+            zenith: zenith.map(|z| z as f32),
+            _padding3: 0,
+            sun_direction: sun.as_vec3().to_array(),
+            _padding4: 0,
+            sun_radiance: sun_radiance.as_vec3().to_array(),
+            _padding5: 0,
+            ground_radiance: ground_radiance.as_vec3().to_array(),
+            _padding6: 0,
+        }
+    }
+
+    // what the sky without the sun gives a horizontal surface, summed up over the upper hemisphere
+    fn sky_irradiance(&self) -> DVec3 {
+        let perez = self.perez();
+        let zenith = self.zenith();
+        let (rows, columns) = (64, 128);
+        let mut sum = DVec3::ZERO;
+        for i in 0..rows {
+            let theta = (i as f64 + 0.5) / rows as f64 * PI / 2.0;
+            let (sin_t, cos_t) = theta.sin_cos();
+            for j in 0..columns {
+                let phi = (j as f64 + 0.5) / columns as f64 * 2.0 * PI;
+                let dir = DVec3::new(sin_t * phi.sin(), cos_t, sin_t * phi.cos());
+                sum += self.radiance(&perez, zenith, dir) * cos_t * sin_t;
+            }
+        }
+        sum * (PI / 2.0 / rows as f64) * (2.0 * PI / columns as f64)
+    }
+
+    // the sun as a light, none once it has set
+    pub(crate) fn sun(&self) -> Option<PunctualLight> {
+        (self.sun_elevation > 0.0).then(|| PunctualLight::Directional {
+            direction: -self.sun_direction(),
+            irradiance: self.sun_irradiance().as_vec3(),
+            angular_radius: SUN_ANGULAR_RADIUS as f32,
+        })
+    }
+}
+
+fn xyy_to_rgb([luminance, x, y]: [f64; 3]) -> DVec3 {
+    if y <= 0.0 {
+        return DVec3::ZERO;
+    }
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+    DVec3::new(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    )
+    .max(DVec3::ZERO)
+}
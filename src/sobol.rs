@@ -0,0 +1,171 @@
+/*
+ * direction numbers of the sobol sequence the sampler in compute.wgsl draws from
+ * the parameters are the first lines of new-joe-kuo-6.21201 by Joe and Kuo,
+ * https://web.maths.unsw.edu.au/~fkuo/sobol/, which are picked for good 2d projections
+ * that covers every primitive polynomial up to degree 8, the shader reuses the
+ * dimensions past that with a differently shuffled sample index
+ */
+pub(crate) const DIMENSIONS: usize = 53;
+
+// the degree s of the primitive polynomial, its inner coefficients a and the s initial m
+const PARAMETERS: [(u32, u32, &[u32]); DIMENSIONS - 1] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+    (6, 19, &[1, 1, 1, 15, 7, 5]),
+    (6, 22, &[1, 3, 1, 15, 13, 25]),
+    (6, 25, &[1, 1, 5, 5, 19, 61]),
+    (7, 1, &[1, 3, 7, 11, 23, 15, 103]),
+    (7, 4, &[1, 3, 7, 13, 13, 15, 69]),
+    (7, 7, &[1, 1, 3, 13, 7, 35, 63]),
+    (7, 8, &[1, 3, 5, 9, 1, 25, 53]),
+    (7, 14, &[1, 3, 1, 13, 9, 35, 107]),
+    (7, 19, &[1, 3, 1, 5, 27, 61, 31]),
+    (7, 21, &[1, 1, 5, 11, 19, 41, 61]),
+    (7, 28, &[1, 3, 5, 3, 3, 13, 69]),
+    (7, 31, &[1, 1, 7, 13, 1, 19, 1]),
+    (7, 32, &[1, 3, 7, 5, 13, 19, 59]),
+    (7, 37, &[1, 1, 3, 9, 25, 29, 41]),
+    (7, 41, &[1, 3, 5, 13, 23, 1, 55]),
+    (7, 42, &[1, 3, 7, 3, 13, 59, 17]),
+    (7, 50, &[1, 3, 1, 3, 5, 53, 69]),
+    (7, 55, &[1, 1, 5, 5, 23, 33, 13]),
+    (7, 56, &[1, 1, 7, 7, 1, 61, 123]),
+    (7, 59, &[1, 1, 7, 9, 13, 61, 49]),
+    (7, 62, &[1, 3, 3, 5, 3, 55, 33]),
+    (8, 14, &[1, 3, 1, 15, 31, 13, 49, 245]),
+    (8, 21, &[1, 3, 5, 15, 31, 59, 63, 97]),
+    (8, 22, &[1, 3, 1, 11, 11, 11, 77, 249]),
+    (8, 38, &[1, 3, 1, 11, 27, 43, 71, 9]),
+    (8, 47, &[1, 1, 7, 15, 21, 11, 81, 45]),
+    (8, 49, &[1, 3, 7, 3, 25, 31, 65, 79]),
+    (8, 50, &[1, 3, 1, 1, 19, 11, 3, 205]),
+    (8, 52, &[1, 1, 5, 9, 19, 21, 29, 157]),
+    (8, 56, &[1, 3, 7, 11, 1, 33, 89, 185]),
+    (8, 67, &[1, 3, 3, 3, 15, 9, 79, 71]),
+    (8, 70, &[1, 3, 7, 11, 15, 39, 119, 27]),
+    (8, 84, &[1, 1, 3, 1, 11, 31, 97, 225]),
+    (8, 97, &[1, 1, 1, 3, 23, 43, 57, 177]),
+    (8, 103, &[1, 3, 7, 7, 17, 17, 37, 71]),
+    (8, 115, &[1, 3, 1, 5, 27, 63, 123, 213]),
+    (8, 122, &[1, 1, 3, 5, 11, 43, 53, 133]),
+];
+
+/*
+ * 32 direction numbers for every dimension, one after the other
+ * bit k of the sample index xors in number k, they are already shifted so
+ * the first bit of the point is the highest bit of the u32
+ */
+pub(crate) fn directions() -> Vec<u32> {
+    // the first dimension has no polynomial, it is the bits of the index mirrored
+    let mut directions = (0..32).map(|k| 1u32 << (31 - k)).collect::<Vec<u32>>();
+    for &(s, a, m) in &PARAMETERS {
+        let s = s as usize;
+        let mut v = [0u32; 32];
+        for k in 0..32 {
+            v[k] = if k < s {
+                m[k] << (31 - k)
+            } else {
+                // the recurrence of the polynomial, see section 2 of joe and kuo's notes
+                let mut x = v[k - s] ^ (v[k - s] >> s);
+                for j in 1..s {
+                    if (a >> (s - 1 - j)) & 1 == 1 {
+                        x ^= v[k - j];
+                    }
+                }
+                x
+            };
+        }
+        directions.extend_from_slice(&v);
+    }
+    directions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // sobol, laine_karras_permutation and nested_uniform_scramble from compute.wgsl
+    fn sobol(directions: &[u32], index: u32, dim: usize) -> u32 {
+        (0..32)
+            .filter(|k| index >> k & 1 == 1)
+            .fold(0, |x, k| x ^ directions[dim * 32 + k])
+    }
+
+    fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+        let mut x = x.wrapping_add(seed);
+        for m in [0x6c50b47cu32, 0xb82f1e52, 0xc7afe638, 0x8d22f6e6] {
+            x ^= x.wrapping_mul(m);
+        }
+        x
+    }
+
+    fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+        laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+    }
+
+    #[test]
+    fn first_points() {
+        let directions = directions();
+        assert_eq!(directions.len(), DIMENSIONS * 32);
+        let points = |dim| {
+            (0..8)
+                .map(|i| sobol(&directions, i, dim) as f64 / 2f64.powi(32))
+                .collect::<Vec<f64>>()
+        };
+        // the van der corput sequence, then the points of x + 1 in index (not gray code) order
+        assert_eq!(
+            points(0),
+            [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]
+        );
+        assert_eq!(
+            points(1),
+            [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875]
+        );
+    }
+
+    /*
+     * the first two dimensions are a (0, 2)-sequence, any 2^k points of an aligned block
+     * have one point in every box of area 2^-k that is a power of two wide and high,
+     * shuffling the index and scrambling like the shader does has to keep that
+     */
+    #[test]
+    fn scrambled_points_stay_stratified() {
+        let directions = directions();
+        for seed in [0u32, 1, 0x9e3779b9, 0xdeadbeef] {
+            let (index_seed, x_seed, y_seed) =
+                (seed, seed.wrapping_mul(747796405), seed ^ 0x2c1b3c6d);
+            for k in 1..=10 {
+                let points = (0..1u32 << k)
+                    .map(|i| {
+                        let index = nested_uniform_scramble(i, index_seed);
+                        (
+                            nested_uniform_scramble(sobol(&directions, index, 0), x_seed),
+                            nested_uniform_scramble(sobol(&directions, index, 1), y_seed),
+                        )
+                    })
+                    .collect::<Vec<(u32, u32)>>();
+                for a in 0..=k {
+                    let boxes = points
+                        .iter()
+                        .map(|&(x, y)| ((x as u64) >> (32 - a), (y as u64) >> (32 - (k - a))))
+                        .collect::<HashSet<(u64, u64)>>();
+                    assert_eq!(boxes.len(), 1 << k, "seed {} k {} a {}", seed, k, a);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::model::{MeshData, Scene, SceneError, single_mesh_scene};
+use std::collections::HashMap;
+use std::path::Path;
+
+// the binary header, then a u32 triangle count
+const HEADER_SIZE: usize = 84;
+// a normal, three corners and a u16 nobody agrees on the meaning of
+const TRIANGLE_SIZE: usize = 50;
+
+/*
+ * load an ascii or binary .stl file as a single mesh
+ * stl repeats every corner for each triangle, corners at the same position are merged
+ * the facet normals are left out, vertex normals are computed like for an obj without them
+ * so curved cad surfaces come out smooth and their edges sharp
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(&bytes)
+        .map_err(|message| SceneError::Malformed {
+            path: path.to_path_buf(),
+            message,
+        })
+        .and_then(|data| single_mesh_scene(path, data))
+}
+
+fn parse(bytes: &[u8]) -> Result<MeshData, String> {
+    /*
+     * binary files are allowed to start with "solid" as well,
+     * but only they have exactly the size their triangle count says
+     */
+    let binary_count = bytes
+        .get(80..HEADER_SIZE)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize);
+    let corners = match binary_count {
+        Some(count) if bytes.len() == HEADER_SIZE + count * TRIANGLE_SIZE => bytes[HEADER_SIZE..]
+            .chunks_exact(TRIANGLE_SIZE)
+            .flat_map(|t| {
+                (0..3).map(move |c| {
+                    let at = 12 + c * 12;
+                    std::array::from_fn(|i| {
+                        f32::from_le_bytes(t[at + i * 4..at + i * 4 + 4].try_into().unwrap())
+                    })
+                })
+            })
+            .collect(),
+        _ if bytes.starts_with(b"solid") => parse_ascii(bytes)?,
+        _ => {
+            return Err(
+                "not an ascii stl, and not as long as its triangle count says for a binary one"
+                    .to_string(),
+            );
+        }
+    };
+
+    let mut data = MeshData::default();
+    let mut ids = HashMap::<[u32; 3], u32>::new();
+    for corner in corners {
+        let id = *ids.entry(corner.map(f32::to_bits)).or_insert_with(|| {
+            data.positions.push(corner);
+            data.positions.len() as u32 - 1
+        });
+        data.indices.push(id);
+    }
+    Ok(data)
+}
+
+// every "vertex x y z" in the file, the facet and loop keywords around them add nothing
+fn parse_ascii(bytes: &[u8]) -> Result<Vec<[f32; 3]>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|e| format!("not valid ascii: {}", e))?;
+    let mut words = text.split_ascii_whitespace();
+    let mut corners = Vec::new();
+    while let Some(word) = words.next() {
+        if word != "vertex" {
+            continue;
+        }
+        let mut corner = [0.0; 3];
+        for c in &mut corner {
+            let w = words.next().unwrap_or_default();
+            *c = w
+                .parse::<f32>()
+                .map_err(|_| format!("'{}' is not a number", w))?;
+        }
+        corners.push(corner);
+    }
+    if corners.len() % 3 != 0 {
+        return Err(format!(
+            "{} vertices do not make whole triangles",
+            corners.len()
+        ));
+    }
+    Ok(corners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two triangles of a square, they share the corners (1, 0, 0) and (0, 1, 0)
+    const SQUARE: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+        [[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((SQUARE.len() as u32).to_le_bytes());
+        for triangle in SQUARE {
+            bytes.extend([0.0f32, 0.0, 1.0].iter().flat_map(|v| v.to_le_bytes()));
+            bytes.extend(triangle.iter().flatten().flat_map(|v| v.to_le_bytes()));
+            bytes.extend([0, 0]);
+        }
+        bytes
+    }
+
+    fn check_square(data: &MeshData) {
+        assert_eq!(data.positions.len(), 4);
+        assert_eq!(data.indices, vec![0, 1, 2, 1, 3, 2]);
+        for (&i, corner) in data.indices.iter().zip(SQUARE.iter().flatten()) {
+            assert_eq!(data.positions[i as usize], *corner);
+        }
+    }
+
+    #[test]
+    fn ascii() {
+        let mut text = "solid square\n".to_string();
+        for triangle in SQUARE {
+            text += "facet normal 0 0 1\nouter loop\n";
+            for [x, y, z] in triangle {
+                text += &format!("vertex {} {} {}\n", x, y, z);
+            }
+            text += "endloop\nendfacet\n";
+        }
+        text += "endsolid square\n";
+        check_square(&parse(text.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn binary_starting_with_solid() {
+        check_square(&parse(&binary(b"solid but actually binary")).unwrap());
+        check_square(&parse(&binary(b"")).unwrap());
+    }
+
+    #[test]
+    fn neither() {
+        let mut bytes = binary(b"");
+        bytes.pop();
+        assert!(parse(&bytes).is_err());
+        assert!(parse(b"solid\nvertex 0 0 0\nvertex 1 0 0\n").is_err());
+        assert!(parse(b"solid\nvertex 0 zero 0\n").is_err());
+    }
+}
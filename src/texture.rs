@@ -0,0 +1,237 @@
+use crate::model::SceneError;
+use image::RgbaImage;
+use std::borrow::Cow;
+use std::path::Path;
+
+pub(crate) const NO_TEXTURE: u32 = u32::MAX;
+
+// bigger textures are scaled down so the array stays within what every gpu allows
+const MAX_SIZE: u32 = 4096;
+
+// the shader finds textures in a fixed size uniform array, 32 bytes each
+const MAX_TEXTURES: usize = 1024;
+
+/*
+ * a texture map of an mtl material, like "map_Bump -bm 0.5 bricks.png"
+ * the file name comes last, of the options we only understand -bm, the bump multiplier
+ * returns the file name and the bump multiplier
+ */
+pub(crate) fn parse_map(map: &str) -> (&str, f32) {
+    let mut words = map.split_whitespace().collect::<Vec<&str>>();
+    let file = words.pop().unwrap_or_default();
+    let bump_scale = words
+        .iter()
+        .position(|&w| w == "-bm")
+        .and_then(|i| words.get(i + 1))
+        .and_then(|s| s.parse::<f32>().ok())
+        .unwrap_or(1.0);
+    (file, bump_scale)
+}
+
+pub(crate) fn load(path: &Path) -> Result<RgbaImage, image::ImageError> {
+    Ok(image::open(path)?.to_rgba8())
+}
+
+// where a texture is in the array, the shader mirrors this as TextureRect
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct TextureRect {
+    // the top left texel in its layer
+    origin: [u32; 2],
+    size: [u32; 2],
+    layer: u32,
+    _padding: [u32; 3],
+}
+
+/*
+ * shelf packing, the textures go from the tallest to the lowest into rows from left to right,
+ * the next row starts below a full one and the next layer once a row does not fit anymore
+ * a layer is as wide as the widest texture and as high as the highest
+ * returns the size of a layer and where every texture ended up
+ */
+pub(crate) fn pack(sizes: &[(u32, u32)]) -> ((u32, u32), Vec<TextureRect>) {
+    let width = sizes.iter().map(|s| s.0).max().unwrap_or(1);
+    let height = sizes.iter().map(|s| s.1).max().unwrap_or(1);
+    let mut order = (0..sizes.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut rects = vec![TextureRect::default(); sizes.len()];
+    let (mut layer, mut x, mut y, mut row_height) = (0, 0, 0, 0);
+    for i in order {
+        let (w, h) = sizes[i];
+        if x + w > width {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+        if y + h > height {
+            layer += 1;
+            x = 0;
+            y = 0;
+        }
+        rects[i] = TextureRect {
+            origin: [x, y],
+            size: [w, h],
+            layer,
+            _padding: [0; 3],
+        };
+        x += w;
+        row_height = row_height.max(h);
+    }
+    ((width, height), rects)
+}
+
+/*
+ * every texture of the scene packed into the layers of one texture array at its own size,
+ * see pack, along with the table of where each one is, padded to the MAX_TEXTURES the shader expects
+ * only textures bigger than MAX_SIZE are scaled down, keeping their aspect ratio
+ * texels are stored as they are, the shader decodes srgb for the color maps
+ */
+pub(crate) fn create_array(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    textures: &[RgbaImage],
+) -> Result<(wgpu::TextureView, Vec<TextureRect>), SceneError> {
+    if textures.len() > MAX_TEXTURES {
+        return Err(SceneError::TooManyTextures {
+            count: textures.len(),
+            limit: MAX_TEXTURES,
+        });
+    }
+    let limits = device.limits();
+    let max_size = MAX_SIZE.min(limits.max_texture_dimension_2d);
+    let textures = textures
+        .iter()
+        .map(|t| {
+            let (w, h) = t.dimensions();
+            if w.max(h) <= max_size {
+                return Cow::Borrowed(t);
+            }
+            let scale = max_size as f32 / w.max(h) as f32;
+            let scaled = |d: u32| ((d as f32 * scale).round() as u32).clamp(1, max_size);
+            Cow::Owned(image::imageops::resize(
+                t,
+                scaled(w),
+                scaled(h),
+                image::imageops::FilterType::Triangle,
+            ))
+        })
+        .collect::<Vec<Cow<RgbaImage>>>();
+
+    let ((width, height), mut rects) = pack(
+        &textures
+            .iter()
+            .map(|t| t.dimensions())
+            .collect::<Vec<(u32, u32)>>(),
+    );
+    /*
+     * the shader never reads the layer when there are no textures, it just has to exist
+     * and with a single layer some drivers sample it as black, so there are always two
+     */
+    let layers = (rects.iter().map(|r| r.layer + 1).max().unwrap_or(0)).max(2);
+    if layers > limits.max_texture_array_layers {
+        return Err(SceneError::TooManyTextureLayers {
+            layers,
+            limit: limits.max_texture_array_layers,
+        });
+    }
+
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Texture Array"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    for (t, rect) in textures.iter().zip(&rects) {
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: rect.origin[0],
+                    y: rect.origin[1],
+                    z: rect.layer,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            t.as_raw(),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * t.width()),
+                rows_per_image: Some(t.height()),
+            },
+            wgpu::Extent3d {
+                width: t.width(),
+                height: t.height(),
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+    rects.resize(MAX_TEXTURES, TextureRect::default());
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    Ok((view, rects))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlap(a: &TextureRect, b: &TextureRect) -> bool {
+        a.layer == b.layer
+            && (0..2).all(|d| {
+                a.origin[d] < b.origin[d] + b.size[d] && b.origin[d] < a.origin[d] + a.size[d]
+            })
+    }
+
+    #[test]
+    fn packed_textures_keep_their_size_and_do_not_overlap() {
+        let sizes = [
+            (64, 64),
+            (16, 8),
+            (64, 32),
+            (32, 32),
+            (8, 64),
+            (32, 16),
+            (64, 64),
+        ];
+        let ((width, height), rects) = pack(&sizes);
+        assert_eq!((width, height), (64, 64));
+        for (i, (rect, &(w, h))) in rects.iter().zip(&sizes).enumerate() {
+            assert_eq!(rect.size, [w, h]);
+            assert!(rect.origin[0] + w <= width && rect.origin[1] + h <= height);
+            for other in &rects[i + 1..] {
+                assert!(!overlap(rect, other), "{:?} overlaps {:?}", rect, other);
+            }
+        }
+    }
+
+    #[test]
+    fn small_textures_share_a_layer() {
+        let ((width, height), rects) = pack(&[(256, 256), (128, 128), (128, 128), (128, 128)]);
+        assert_eq!((width, height), (256, 256));
+        assert_eq!(rects.iter().map(|r| r.layer).max(), Some(1));
+        assert!(rects[1..].iter().all(|r| r.layer == 1));
+    }
+
+    #[test]
+    fn no_textures() {
+        assert_eq!(pack(&[]), ((1, 1), vec![]));
+    }
+
+    #[test]
+    fn bump_multiplier() {
+        assert_eq!(parse_map("-bm 0.5 bricks.png"), ("bricks.png", 0.5));
+        assert_eq!(parse_map("bricks.png"), ("bricks.png", 1.0));
+    }
+}